@@ -7,7 +7,9 @@ use aes_soft::Aes128;
 use block_modes::block_padding::Pkcs7;
 use block_modes::{BlockMode, Cbc};
 use rand::rngs::OsRng;
+use rsa::hash::Hashes;
 use rsa::{PaddingScheme, PublicKey, RSAPrivateKey, RSAPublicKey};
+use sha2::{Digest, Sha256};
 
 use crate::io;
 use crate::util;
@@ -150,6 +152,31 @@ pub fn decrypt_sym(k: &[u8], iv: &[u8], ciphertext: &[u8]) -> Vec<u8> {
     cipher.decrypt(&mut buf).unwrap().to_vec()
 }
 
+/// Verifies a base64 encoded PKCS#1 v1.5 signature over the SHA-256 digest of `data`.
+pub fn verify_signature(data: &[u8], signature_b64: &str, public_key: &RSAPublicKey) -> bool {
+    let signature: Vec<u8> = match base64::decode(signature_b64.trim()) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let digest = Sha256::digest(data);
+    public_key
+        .verify(
+            PaddingScheme::PKCS1v15,
+            Some(&Hashes::SHA2_256),
+            &digest,
+            &signature,
+        )
+        .is_ok()
+}
+
+/// SHA-256 over the DER bytes of a PEM encoded public key, as lowercase hex pairs separated by ':'
+pub fn fingerprint(pub_key: &str) -> Result<String, pem::PemError> {
+    let pem_encoded = pem::parse(pub_key)?;
+    let digest = Sha256::digest(&pem_encoded.contents);
+    let hex: Vec<String> = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(hex.join(":"))
+}
+
 pub fn generate_random_hex_16() -> [u8; 16] {
     let cmd = Command::new("openssl")
         .arg("rand")
@@ -431,6 +458,17 @@ mod tests {
         std::fs::remove_file(&file_name).expect("message.test not found or permission denied");
     }
 
+    #[test]
+    fn test_fingerprint() {
+        let mut file = File::open("./src/test/test.pem.pub").unwrap();
+        let mut file_content = String::new();
+        file.read_to_string(&mut file_content).unwrap();
+        let actual: String = fingerprint(&file_content).unwrap();
+        assert_eq!(actual.len(), 32 * 3 - 1);
+        assert_eq!(actual, fingerprint(&file_content).unwrap());
+        assert_eq!(fingerprint("not a key").is_err(), true);
+    }
+
     fn read_public_key() -> Result<RSAPublicKey> {
         let mut file = File::open("./src/test/test.pem.pub")?;
         let mut file_content = String::new();
@@ -0,0 +1,246 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::vec::Vec;
+
+use rsa::RSAPublicKey;
+
+use crate::crypto;
+
+#[derive(Debug)]
+pub struct DirectoryError {
+    reason: String,
+}
+
+impl DirectoryError {
+    pub fn new(reason: &str) -> DirectoryError {
+        DirectoryError {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl Error for DirectoryError {}
+
+impl Display for DirectoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rejected server response: {}", self.reason)
+    }
+}
+
+/// A directory snapshot signed by the server, see `server::pubkey_directory`.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub serial: i64,
+    pub timestamp: u64,
+    pub users: Vec<String>,
+}
+
+/// Checks the server's signature over a `{"payload": ..., "signature": ...}` response with the pinned server key
+/// and returns the parsed payload.
+pub fn verify_signed(
+    resp: &serde_json::Value,
+    server_key: &RSAPublicKey,
+) -> Result<serde_json::Value, DirectoryError> {
+    let payload: &str = resp["payload"]
+        .as_str()
+        .ok_or_else(|| DirectoryError::new("missing payload"))?;
+    let signature: &str = resp["signature"]
+        .as_str()
+        .ok_or_else(|| DirectoryError::new("missing signature"))?;
+    if !crypto::verify_signature(payload.as_bytes(), signature, server_key) {
+        return Err(DirectoryError::new("bad signature"));
+    }
+    serde_json::from_str(payload).map_err(|_| DirectoryError::new("malformed payload"))
+}
+
+/// Verifies the snapshot signature, then rejects any snapshot older than the last one this client accepted.
+pub fn verify_snapshot(
+    resp: &serde_json::Value,
+    server_key: &RSAPublicKey,
+    last_serial: i64,
+) -> Result<Snapshot, DirectoryError> {
+    let snapshot: serde_json::Value = verify_signed(resp, server_key)?;
+    parse_snapshot(&snapshot, last_serial)
+}
+
+/// Verifies a signed `/changes` delta and that it applies to the `since` serial this client asked for.
+/// `users` of the returned snapshot holds only the changed users.
+pub fn verify_changes(
+    resp: &serde_json::Value,
+    server_key: &RSAPublicKey,
+    since: i64,
+) -> Result<Snapshot, DirectoryError> {
+    let changes: serde_json::Value = verify_signed(resp, server_key)?;
+    if changes["since"].as_i64() != Some(since) {
+        return Err(DirectoryError::new(&format!(
+            "changes are not relative to serial {}",
+            since
+        )));
+    }
+    parse_snapshot(&changes, since)
+}
+
+fn parse_snapshot(
+    snapshot: &serde_json::Value,
+    last_serial: i64,
+) -> Result<Snapshot, DirectoryError> {
+    let serial: i64 = snapshot["serial"]
+        .as_i64()
+        .ok_or_else(|| DirectoryError::new("missing serial"))?;
+    if serial < last_serial {
+        return Err(DirectoryError::new(&format!(
+            "serial {} is older than the last seen serial {}",
+            serial, last_serial
+        )));
+    }
+    let timestamp: u64 = snapshot["timestamp"]
+        .as_u64()
+        .ok_or_else(|| DirectoryError::new("missing timestamp"))?;
+    let users: Vec<String> = serde_json::from_value(snapshot["users"].clone())
+        .map_err(|_| DirectoryError::new("malformed users"))?;
+
+    Ok(Snapshot {
+        serial,
+        timestamp,
+        users,
+    })
+}
+
+/// Applies changed `id,name,pem` lines to the stored ones: a changed user replaces the line with the same id,
+/// a new user is appended. A user whose key was revoked, or who was deleted (sent as a tombstone without a key),
+/// is removed so nothing is encrypted to their old key.
+pub fn merge_users(existing: Vec<String>, changed: Vec<String>) -> Vec<String> {
+    let id = |line: &str| line.split(',').next().unwrap_or("").to_string();
+    let has_key = |line: &str| {
+        line.splitn(3, ',')
+            .nth(2)
+            .map_or(false, |pem| !pem.is_empty())
+    };
+    let mut merged: Vec<String> = existing;
+    for line in changed {
+        let user_id: String = id(&line);
+        let keyed: bool = has_key(&line);
+        match merged.iter().position(|l| id(l) == user_id) {
+            Some(i) if keyed => merged[i] = line,
+            Some(i) => {
+                merged.remove(i);
+            }
+            None if keyed => merged.push(line),
+            None => {}
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::hash::Hashes;
+    use rsa::{PaddingScheme, RSAPrivateKey};
+    use sha2::{Digest, Sha256};
+    use std::convert::TryFrom;
+
+    use crate::io;
+
+    #[test]
+    fn test_verify_snapshot() {
+        let pem_encoded =
+            pem::parse(io::load_contents_from_file("./src/test/test.pem").unwrap()).unwrap();
+        let private_key: RSAPrivateKey = RSAPrivateKey::try_from(pem_encoded).unwrap();
+        let public_key: RSAPublicKey =
+            io::parse_public_key(&io::load_contents_from_file("./src/test/test.pem.pub").unwrap())
+                .unwrap();
+
+        let payload: &str = r#"{"serial":7,"timestamp":1600000000,"users":["U1234ABC,jeff,"]}"#;
+        let digest = Sha256::digest(payload.as_bytes());
+        let signature: Vec<u8> = private_key
+            .sign(PaddingScheme::PKCS1v15, Some(&Hashes::SHA2_256), &digest)
+            .unwrap();
+        let resp = serde_json::json!({
+            "payload": payload,
+            "signature": base64::encode(&signature),
+        });
+
+        let snapshot: Snapshot = verify_snapshot(&resp, &public_key, 7).unwrap();
+        assert_eq!(snapshot.serial, 7);
+        assert_eq!(snapshot.users, vec!["U1234ABC,jeff,".to_string()]);
+        assert_eq!(verify_snapshot(&resp, &public_key, 8).is_err(), true);
+
+        let tampered = serde_json::json!({
+            "payload": payload.replace("jeff", "mallory"),
+            "signature": base64::encode(&signature),
+        });
+        assert_eq!(verify_snapshot(&tampered, &public_key, 0).is_err(), true);
+    }
+
+    #[test]
+    fn test_merge_tombstone() {
+        let pem_encoded =
+            pem::parse(io::load_contents_from_file("./src/test/test.pem").unwrap()).unwrap();
+        let private_key: RSAPrivateKey = RSAPrivateKey::try_from(pem_encoded).unwrap();
+        let public_key: RSAPublicKey =
+            io::parse_public_key(&io::load_contents_from_file("./src/test/test.pem.pub").unwrap())
+                .unwrap();
+
+        // rade was deleted, jeff's key was revoked
+        let payload: &str = r#"{"since":7,"serial":9,"timestamp":1600000000,"users":["U1234ABC,jeff,","U5678DEF,rade,"]}"#;
+        let digest = Sha256::digest(payload.as_bytes());
+        let signature: Vec<u8> = private_key
+            .sign(PaddingScheme::PKCS1v15, Some(&Hashes::SHA2_256), &digest)
+            .unwrap();
+        let resp = serde_json::json!({
+            "payload": payload,
+            "signature": base64::encode(&signature),
+        });
+
+        let changes: Snapshot = verify_changes(&resp, &public_key, 7).unwrap();
+        let existing: Vec<String> = vec![
+            "U1234ABC,jeff,KEY1".to_string(),
+            "U5678DEF,rade,KEY2".to_string(),
+            "U9999XYZ,tim,KEY3".to_string(),
+        ];
+        assert_eq!(
+            merge_users(existing, changes.users),
+            vec!["U9999XYZ,tim,KEY3".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_verify_changes() {
+        let pem_encoded =
+            pem::parse(io::load_contents_from_file("./src/test/test.pem").unwrap()).unwrap();
+        let private_key: RSAPrivateKey = RSAPrivateKey::try_from(pem_encoded).unwrap();
+        let public_key: RSAPublicKey =
+            io::parse_public_key(&io::load_contents_from_file("./src/test/test.pem.pub").unwrap())
+                .unwrap();
+
+        let payload: &str =
+            r#"{"since":7,"serial":9,"timestamp":1600000000,"users":["U1234ABC,jeff,KEY"]}"#;
+        let digest = Sha256::digest(payload.as_bytes());
+        let signature: Vec<u8> = private_key
+            .sign(PaddingScheme::PKCS1v15, Some(&Hashes::SHA2_256), &digest)
+            .unwrap();
+        let resp = serde_json::json!({
+            "payload": payload,
+            "signature": base64::encode(&signature),
+        });
+
+        let changes: Snapshot = verify_changes(&resp, &public_key, 7).unwrap();
+        assert_eq!(changes.serial, 9);
+        assert_eq!(verify_changes(&resp, &public_key, 8).is_err(), true);
+    }
+
+    #[test]
+    fn test_merge_users() {
+        let existing: Vec<String> = vec!["U1,jeff,".to_string(), "U2,rade,KEY2".to_string()];
+        let changed: Vec<String> = vec!["U1,jeff,KEY1".to_string(), "U3,new,KEY3".to_string()];
+        assert_eq!(
+            merge_users(existing, changed),
+            vec![
+                "U1,jeff,KEY1".to_string(),
+                "U2,rade,KEY2".to_string(),
+                "U3,new,KEY3".to_string()
+            ]
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::error::Error;
+use std::time::Duration;
+use std::vec::Vec;
+
+use crate::prop;
+
+/// Seconds to wait before reconnecting a dropped event stream.
+const RECONNECT_SECS: u64 = 30;
+
+/// A key change pushed by the server's `/events` stream. It carries no key, the signed `/changes`
+/// delta is downloaded to apply it.
+#[derive(Debug, PartialEq)]
+pub struct KeyEvent {
+    pub kind: String,
+    pub user_id: String,
+    pub name: String,
+    pub fingerprint: Option<String>,
+    pub revision: i64,
+}
+
+impl KeyEvent {
+    pub fn describe(&self) -> String {
+        let fingerprint: &str = self.fingerprint.as_deref().unwrap_or("unknown");
+        match self.kind.as_str() {
+            "key-added" => format!("{} registered a public key ({})", self.name, fingerprint),
+            "key-rotated" => format!(
+                "{} rotated their public key, new fingerprint {}",
+                self.name, fingerprint
+            ),
+            "key-revoked" => format!("{} revoked their public key ({})", self.name, fingerprint),
+            other => format!("{}: {}", self.name, other),
+        }
+    }
+}
+
+/// Parses one `text/event-stream` event, `None` for comments and events without key data.
+pub fn parse_event(block: &str) -> Option<KeyEvent> {
+    let mut kind: String = String::from("message");
+    let mut data: String = String::new();
+    for line in block.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            kind = value.trim().to_string();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push_str(value.trim());
+        }
+    }
+    let data: serde_json::Value = serde_json::from_str(&data).ok()?;
+    Some(KeyEvent {
+        kind,
+        user_id: data["user_id"].as_str()?.to_string(),
+        name: data["name"].as_str()?.to_string(),
+        fingerprint: data["fingerprint"].as_str().map(|f| f.to_string()),
+        revision: data["revision"].as_i64()?,
+    })
+}
+
+/// Removes every complete event (terminated by a blank line) from the front of `buf`.
+pub fn take_events(buf: &mut Vec<u8>) -> Vec<KeyEvent> {
+    buf.retain(|b| *b != b'\r');
+    let mut events: Vec<KeyEvent> = Vec::new();
+    while let Some(end) = buf.windows(2).position(|w| w == b"\n\n") {
+        let block: Vec<u8> = buf.drain(..end + 2).collect();
+        if let Some(event) = parse_event(&String::from_utf8_lossy(&block)) {
+            events.push(event);
+        }
+    }
+    events
+}
+
+/// Follows the event stream at `url` forever, reconnecting after errors.
+#[tokio::main]
+pub async fn listen<F: FnMut(KeyEvent)>(url: &str, mut on_event: F) {
+    loop {
+        match stream(url, &mut on_event).await {
+            Ok(_) => log::info!("Key event stream closed by the server"),
+            Err(e) => log::warn!("Key event stream failed: {}", e),
+        }
+        tokio::time::sleep(Duration::from_secs(RECONNECT_SECS)).await;
+    }
+}
+
+/// The API token is read on every connect, so one set after the client started is picked up.
+async fn stream<F: FnMut(KeyEvent)>(url: &str, on_event: &mut F) -> Result<(), Box<dyn Error>> {
+    let api_token: String = prop::get_property("api_token", "");
+    let mut resp = reqwest::Client::new()
+        .get(url)
+        .bearer_auth(api_token)
+        .header("Accept", "text/event-stream")
+        .send()
+        .await?
+        .error_for_status()?;
+    log::info!("Subscribed to key events at {}", url);
+
+    let mut buf: Vec<u8> = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        buf.extend_from_slice(&chunk);
+        for event in take_events(&mut buf) {
+            on_event(event);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_events() {
+        let mut buf: Vec<u8> = b": connected\n\nevent: key-rotated\nid: 7\ndata: {\"fingerprint\":\"ab:cd\",\"name\":\"jeff\",\"revision\":7,\"user_id\":\"U1234ABC\"}\n\nevent: key-added\n".to_vec();
+        let events: Vec<KeyEvent> = take_events(&mut buf);
+        assert_eq!(
+            events,
+            vec![KeyEvent {
+                kind: "key-rotated".to_string(),
+                user_id: "U1234ABC".to_string(),
+                name: "jeff".to_string(),
+                fingerprint: Some("ab:cd".to_string()),
+                revision: 7,
+            }]
+        );
+        // the incomplete event stays buffered
+        assert_eq!(buf, b"event: key-added\n".to_vec());
+    }
+}
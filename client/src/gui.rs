@@ -1,16 +1,31 @@
-use fltk::{app::*, button::*, input::*, menu::*, text::*, tree::*, window::Window};
+use fltk::{app::*, button::*, dialog, input::*, menu::*, text::*, tree::*, window::Window};
 use rsa::RSAPublicKey;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::error::Error;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
 
 use crate::crypto;
+use crate::directory;
+use crate::events;
 use crate::io;
+use crate::pins;
+use crate::pins::PinStatus;
 use crate::prop;
+use crate::registration;
+use crate::transparency;
 use crate::util;
 
 #[derive(Copy, Clone)]
 pub enum Message {
     New,
     Users,
+    Review,
+    Token,
+    Register,
+    KeysChanged,
     Quit,
 }
 
@@ -37,13 +52,13 @@ pub fn init(window_label: &str) {
 
     //Outputs
     let mut armored_out = build_text_display(padding, 100, armored_out_width - 2 * padding, 150);
-    let mut users_tree = build_users_tree_select(
+    let users_tree: Rc<RefCell<Tree>> = Rc::new(RefCell::new(build_users_tree_select(
         armored_out_width,
         100,
         users_display_width - padding,
         150,
         &users,
-    );
+    )));
     let mut plaintext_out = build_text_display(padding, 490, window_width - 2 * padding, 40);
 
     //Buttons
@@ -70,14 +85,19 @@ pub fn init(window_label: &str) {
     let mut menu = MenuBar::new(0, 0, window_width, 40, "");
     init_menu(&mut menu, s);
 
+    let (events_tx, events_rx) = mpsc::channel::<events::KeyEvent>();
+    start_event_listener(s, events_tx);
+
     //Event handling must be done after the drawing is done and the main `window` shown. And must be done in the main thread.
     window.make_resizable(true);
     window.end();
     window.show();
 
     //Button events
+    let selected_tree: Rc<RefCell<Tree>> = users_tree.clone();
     encrypt_button.set_callback(Box::new(move || {
-        let user_name: String = match users_tree.get_selected_items() {
+        let users: HashMap<String, (String, String)> = io::read_users_file();
+        let user_name: String = match selected_tree.borrow().get_selected_items() {
             Some(tree_users) => {
                 let user_name: String = tree_users.as_slice()[0].label().unwrap();
                 log::debug!("user {} was selected", &user_name);
@@ -99,7 +119,17 @@ pub fn init(window_label: &str) {
         }
 
         let input: String = plaintext_in.value();
-        let result: String = encrypt_text(&input, &pub_key, &user_id);
+        let result: String = if !is_key_trusted(&user_name, &user_id, &pub_key) {
+            format!(
+                "Encryption to {} is blocked until their new public key is accepted.",
+                &user_name
+            )
+        } else if let Err(e) = verify_key_logged(&user_id, &pub_key) {
+            log::error!("{}", e);
+            format!("Encryption to {} is blocked: {}", &user_name, e)
+        } else {
+            encrypt_text(&input, &pub_key, &user_id)
+        };
         armored_out.set_buffer(TextBuffer::default());
         armored_out.buffer().append(&result);
     }));
@@ -128,6 +158,23 @@ pub fn init(window_label: &str) {
                 Users => {
                     get_user_pubkeys();
                 }
+                Review => {
+                    review_key_changes(pins::pending_changes());
+                }
+                Token => {
+                    set_api_token();
+                }
+                Register => {
+                    register_public_key();
+                }
+                KeysChanged => {
+                    let changes: Vec<String> = events_rx.try_iter().map(|e| e.describe()).collect();
+                    if !changes.is_empty() {
+                        get_user_pubkeys();
+                        populate_users_tree(&mut users_tree.borrow_mut(), &io::read_users_file());
+                        dialog::message(200, 200, &changes.join("\n"));
+                    }
+                }
                 Quit => {
                     app.quit();
                 }
@@ -152,13 +199,33 @@ fn build_users_tree_select(
 ) -> Tree {
     let mut tree = Tree::new(x, y, w, h, "");
     tree.set_select_mode(TreeSelect::Single);
-    tree.set_root_label("Slack Users");
+    populate_users_tree(&mut tree, users);
+    tree
+}
 
+fn populate_users_tree(tree: &mut Tree, users: &HashMap<String, (String, String)>) {
+    tree.clear();
     for name in users.keys() {
         tree.add(&name);
     }
+    tree.set_root_label("Slack Users");
+    tree.redraw();
+}
 
-    tree
+/// Follows the server's key events in the background. Each event is queued for the main loop,
+/// which applies it by syncing the signed directory.
+fn start_event_listener(s: Sender<Message>, events_tx: mpsc::Sender<events::KeyEvent>) {
+    let base_url: String = prop::get_property("server_base_url", "http://127.0.0.1:8080");
+    // init.sh writes the server's events_url, the fallback is where the example nginx config proxies the stream
+    let url: String = prop::get_property("server_events_url", &(base_url + "/events"));
+    thread::spawn(move || {
+        events::listen(&url, |event| {
+            log::info!("{}", event.describe());
+            if events_tx.send(event).is_ok() {
+                s.send(Message::KeysChanged);
+            }
+        });
+    });
 }
 
 fn init_menu(menu: &mut MenuBar, s: Sender<Message>) {
@@ -176,6 +243,27 @@ fn init_menu(menu: &mut MenuBar, s: Sender<Message>) {
         Box::new(move || s.send(Message::Users)),
     );
 
+    menu.add(
+        "File/Review Changed Keys",
+        Shortcut::None,
+        MenuFlag::Normal,
+        Box::new(move || s.send(Message::Review)),
+    );
+
+    menu.add(
+        "File/Set API Token",
+        Shortcut::None,
+        MenuFlag::Normal,
+        Box::new(move || s.send(Message::Token)),
+    );
+
+    menu.add(
+        "File/Register Public Key",
+        Shortcut::None,
+        MenuFlag::Normal,
+        Box::new(move || s.send(Message::Register)),
+    );
+
     menu.add(
         "File/Quit",
         Shortcut::None,
@@ -205,23 +293,202 @@ fn decrypt_text(armored_msg: &str) -> String {
     }
 }
 
+/// Keys are trusted when they match the pinned fingerprint, otherwise the user has to accept the new one first.
+fn is_key_trusted(user_name: &str, user_id: &str, pub_key: &str) -> bool {
+    if user_id == "self" {
+        return true;
+    }
+    match pins::key_status(user_id, pub_key) {
+        PinStatus::Trusted | PinStatus::Unpinned => true,
+        PinStatus::Changed { pinned, presented } => review_key_change(&pins::KeyChange {
+            user_id: user_id.to_string(),
+            user_name: user_name.to_string(),
+            pinned,
+            presented,
+        }),
+    }
+}
+
+fn verify_key_logged(user_id: &str, pub_key: &str) -> Result<(), Box<dyn Error>> {
+    if user_id == "self" {
+        return Ok(());
+    }
+    transparency::verify_key(user_id, pub_key)
+}
+
+fn review_key_change(change: &pins::KeyChange) -> bool {
+    log::warn!("{}", change.describe());
+    let msg: String = format!(
+        "{}\n\nOnly accept after verifying the new fingerprint with {} directly.",
+        change.describe(),
+        change.user_name
+    );
+    if dialog::choice(200, 200, &msg, "Reject", "Accept", "") == 1 {
+        pins::accept(&change.user_id, &change.presented).unwrap();
+        true
+    } else {
+        false
+    }
+}
+
+fn review_key_changes(changes: Vec<pins::KeyChange>) {
+    if changes.is_empty() {
+        dialog::message(200, 200, "No changed public keys to review.");
+    }
+    for change in changes {
+        review_key_change(&change);
+    }
+}
+
+/// Stores the API token the bot DMs with `/slackrypt token` as `api_token` in `slackrypt.properties`.
+fn set_api_token() {
+    let msg: &str =
+        "Paste the API token the Slackrypt bot sent you (ask it with /slackrypt token):";
+    if let Some(token) = dialog::input(200, 200, msg, "") {
+        if !token.trim().is_empty() {
+            prop::upsert_property("api_token", token.trim()).unwrap();
+        }
+    }
+}
+
+/// Submits `key.pem.pub` to the server, e.g. when there was no API token yet when the key pair was created.
+fn register_public_key() {
+    match registration::submit_public_key() {
+        Ok(fingerprint) => {
+            dialog::message(200, 200, &registration::confirmation_message(&fingerprint))
+        }
+        Err(e) => {
+            log::error!("Could not register public key: {}", e);
+            dialog::alert(200, 200, &format!("Could not register public key: {}", e));
+        }
+    }
+}
+
 fn get_user_pubkeys() {
-    let user_pubkeys: Vec<String> = get_pubkeys().unwrap();
-    io::update_users_file(user_pubkeys).unwrap();
+    if let Err(e) = sync_pubkeys() {
+        log::error!("Could not download public keys: {}", e);
+        dialog::alert(200, 200, &format!("Could not download public keys: {}", e));
+        return;
+    }
+
+    let users: HashMap<String, (String, String)> = io::read_users_file();
+    let changes: Vec<pins::KeyChange> = pins::pin_users(&users).unwrap();
+    if !changes.is_empty() {
+        let mut msg: String = String::from(
+            "WARNING: public keys changed since they were first seen. Encryption to these users is blocked until you review them (File/Review Changed Keys):\n",
+        );
+        for change in &changes {
+            log::warn!("{}", change.describe());
+            msg.push('\n');
+            msg.push_str(&change.describe());
+            msg.push('\n');
+        }
+        dialog::alert(200, 200, &msg);
+    }
+
+    audit_own_key(&users);
 }
 
+/// Checks the transparency log for keys published under this client's Slack user that are not its own.
+fn audit_own_key(users: &HashMap<String, (String, String)>) {
+    let own_pub_key: String = io::get_public_key_string(&util::default_dir()).unwrap();
+    let mut user_id: String = prop::get_property("slack_user_id", "");
+    if user_id.is_empty() {
+        let own_fingerprint = crypto::fingerprint(&own_pub_key).ok();
+        match users
+            .values()
+            .find(|(_, key)| crypto::fingerprint(key).ok() == own_fingerprint)
+        {
+            Some((id, _)) => {
+                prop::upsert_property("slack_user_id", id).unwrap();
+                user_id.push_str(id);
+            }
+            None => {
+                log::info!("Own public key is not registered yet, skipping audit.");
+                return;
+            }
+        }
+    }
+
+    if let Err(e) = transparency::audit_own_key(&user_id, &own_pub_key) {
+        log::error!("Own key audit failed: {}", e);
+        dialog::alert(200, 200, &format!("WARNING: {}", e));
+    }
+}
+
+/// Brings `slackrypt.users` up to date. After the first full snapshot only the changes since the
+/// stored `directory_serial` are downloaded, or nothing when the server answers 304 Not Modified.
 #[tokio::main]
-async fn get_pubkeys() -> Result<Vec<String>, reqwest::Error> {
+async fn sync_pubkeys() -> Result<(), Box<dyn Error>> {
     let base_url: String = prop::get_property("server_base_url", "http://127.0.0.1:8080");
-    let endpoint: String = base_url + "/pubkey/users";
-    let json_resp: serde_json::Value = reqwest::Client::new()
+    let server_key: RSAPublicKey = io::get_server_public_key(&util::default_dir())?;
+    let api_token: String = prop::get_property("api_token", "");
+    let last_serial: i64 = prop::get_property("directory_serial", "0").parse::<i64>()?;
+    if last_serial == 0 || !io::users_file_exists() {
+        return get_pubkeys(&base_url, &api_token, &server_key, last_serial).await;
+    }
+
+    let endpoint: String = format!("{}/changes?since={}", base_url, last_serial);
+    let resp = reqwest::Client::new()
         .get(&endpoint)
+        .bearer_auth(&api_token)
+        .header("If-None-Match", format!("\"{}\"", last_serial))
         .send()
-        .await?
-        .json()
         .await?;
+    let resp = authorized(resp)?;
+    if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+        log::info!("Directory serial {} is up to date", last_serial);
+        return Ok(());
+    }
+    let json_resp: serde_json::Value = resp.json().await?;
+    let changes: directory::Snapshot =
+        directory::verify_changes(&json_resp, &server_key, last_serial)?;
+    log::info!(
+        "Verified {} changed users up to directory serial {} signed at {}",
+        changes.users.len(),
+        changes.serial,
+        changes.timestamp
+    );
+    io::update_users_file(directory::merge_users(
+        io::read_users_lines()?,
+        changes.users,
+    ))?;
+    prop::upsert_property("directory_serial", &changes.serial.to_string())?;
+    Ok(())
+}
+
+/// The directory routes answer `401 Unauthorized` without a valid `api_token`.
+fn authorized(resp: reqwest::Response) -> Result<reqwest::Response, Box<dyn Error>> {
+    if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(Box::new(directory::DirectoryError::new(
+            "the server needs a valid API token, ask the Slackrypt bot for one with /slackrypt token and paste it in File/Set API Token",
+        )));
+    }
+    Ok(resp.error_for_status()?)
+}
 
-    let resp: String = json_resp.to_string();
-    let pubkeys: Vec<String> = serde_json::from_str(&resp).unwrap();
-    Ok(pubkeys)
+async fn get_pubkeys(
+    base_url: &str,
+    api_token: &str,
+    server_key: &RSAPublicKey,
+    last_serial: i64,
+) -> Result<(), Box<dyn Error>> {
+    let endpoint: String = base_url.to_string() + "/pubkey/directory";
+    let resp = reqwest::Client::new()
+        .get(&endpoint)
+        .bearer_auth(api_token)
+        .send()
+        .await?;
+    let json_resp: serde_json::Value = authorized(resp)?.json().await?;
+
+    let snapshot: directory::Snapshot =
+        directory::verify_snapshot(&json_resp, server_key, last_serial)?;
+    log::info!(
+        "Verified directory serial {} signed at {}",
+        snapshot.serial,
+        snapshot.timestamp
+    );
+    io::update_users_file(snapshot.users)?;
+    prop::upsert_property("directory_serial", &snapshot.serial.to_string())?;
+    Ok(())
 }
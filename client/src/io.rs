@@ -24,6 +24,12 @@ pub fn get_public_key_string(dir: &str) -> Result<String> {
     Ok(file_content)
 }
 
+/// The server signing key pinned by `init.sh` at install time.
+pub fn get_server_public_key(dir: &str) -> Result<RSAPublicKey> {
+    let file_content: String = load_contents_from_file(&(String::from(dir) + "/server.pem.pub"))?;
+    parse_public_key(&file_content)
+}
+
 pub fn parse_public_key(pub_key: &str) -> Result<RSAPublicKey> {
     let pem_encoded = pem::parse(pub_key).expect("failed to parse pem file");
     let public_key = RSAPublicKey::try_from(pem_encoded).expect("failed to parse key");
@@ -64,6 +70,16 @@ pub fn update_users_file(users: Vec<String>) -> Result<()> {
     f.write_all(s.as_bytes())
 }
 
+pub fn users_file_exists() -> bool {
+    std::path::Path::new(&(util::default_dir() + USERS_FILE_NAME)).exists()
+}
+
+/// The stored `id,name,pem` lines as downloaded, including users without a key.
+pub fn read_users_lines() -> Result<Vec<String>> {
+    let file = File::open(util::default_dir() + USERS_FILE_NAME)?;
+    BufReader::new(file).lines().collect()
+}
+
 pub fn read_users_file() -> HashMap<String, (String, String)> {
     let mut user_pubkey_map = HashMap::new();
     let file_name: String = util::default_dir() + USERS_FILE_NAME;
@@ -6,9 +6,14 @@ use rsa::RSAPublicKey;
 use simple_logger::SimpleLogger;
 
 mod crypto;
+mod directory;
+mod events;
 mod gui;
 mod io;
+mod pins;
 mod prop;
+mod registration;
+mod transparency;
 mod util;
 
 fn main() {
@@ -60,5 +65,17 @@ fn init(dir: &str) {
         let bits_str: String = util::get_env_var("SCRYPT_KEY_SIZE", "2048");
         let bits: i32 = bits_str.parse::<i32>().unwrap();
         crypto::create_keys_asym(bits, &key_file);
+        match registration::submit_public_key() {
+            Ok(fingerprint) => println!("{}", registration::confirmation_message(&fingerprint)),
+            Err(e) => {
+                eprintln!("Could not register your public key: {}", e);
+                eprintln!("Register it later with File/Register Public Key.");
+            }
+        }
+    }
+
+    for change in pins::pending_changes() {
+        eprintln!("WARNING: {}", change.describe());
+        eprintln!("Encryption to this user is blocked until the new key is accepted in File/Review Changed Keys.");
     }
 }
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::io::Result;
+
+use crate::crypto;
+use crate::io;
+use crate::util;
+
+const PINS_FILE_NAME: &str = "/slackrypt.pins";
+
+/// Trust-on-first-use state of a user's public key compared to the fingerprint pinned for them.
+#[derive(Debug, PartialEq)]
+pub enum PinStatus {
+    Trusted,
+    Unpinned,
+    Changed { pinned: String, presented: String },
+}
+
+/// A user whose downloaded public key no longer matches the pinned fingerprint.
+#[derive(Debug)]
+pub struct KeyChange {
+    pub user_id: String,
+    pub user_name: String,
+    pub pinned: String,
+    pub presented: String,
+}
+
+impl KeyChange {
+    pub fn describe(&self) -> String {
+        format!(
+            "The public key for {} ({}) has changed!\n\nPinned: {}\nNew:    {}",
+            self.user_name, self.user_id, self.pinned, self.presented
+        )
+    }
+}
+
+pub fn read_pins_file() -> HashMap<String, String> {
+    let mut pins = HashMap::new();
+    let file_name: String = util::default_dir() + PINS_FILE_NAME;
+    match File::open(&file_name) {
+        Ok(file) => {
+            let reader = BufReader::new(file);
+            for l in reader.lines() {
+                let line: String = l.unwrap();
+                let kv: Vec<&str> = line.splitn(2, ',').collect();
+                if kv.len() == 2 && !kv[1].is_empty() {
+                    pins.insert(String::from(kv[0]), String::from(kv[1]));
+                }
+            }
+        }
+        Err(_e) => {
+            log::warn!("slackrypt.pins file does not yet exist.");
+        }
+    }
+    pins
+}
+
+fn write_pins_file(pins: &HashMap<String, String>) -> Result<()> {
+    let path = util::default_dir() + PINS_FILE_NAME;
+    let mut f = File::create(path)?;
+
+    let mut s = String::new();
+    for (user_id, fingerprint) in pins {
+        s.push_str(user_id);
+        s.push(',');
+        s.push_str(fingerprint);
+        s.push('\n');
+    }
+
+    f.write_all(s.as_bytes())
+}
+
+pub fn status(pins: &HashMap<String, String>, user_id: &str, pub_key: &str) -> PinStatus {
+    let presented: String = match crypto::fingerprint(pub_key) {
+        Ok(fingerprint) => fingerprint,
+        Err(e) => {
+            log::error!("Could not fingerprint public key of {}: {}", user_id, e);
+            String::new()
+        }
+    };
+    match pins.get(user_id) {
+        None => PinStatus::Unpinned,
+        Some(pinned) if pinned == &presented => PinStatus::Trusted,
+        Some(pinned) => PinStatus::Changed {
+            pinned: pinned.clone(),
+            presented,
+        },
+    }
+}
+
+pub fn key_status(user_id: &str, pub_key: &str) -> PinStatus {
+    status(&read_pins_file(), user_id, pub_key)
+}
+
+/// Pins every user seen for the first time and returns the users whose key changed since it was pinned.
+/// Changed keys are never re-pinned here, that only happens through `accept`.
+pub fn pin_users(users: &HashMap<String, (String, String)>) -> Result<Vec<KeyChange>> {
+    let mut pins: HashMap<String, String> = read_pins_file();
+    let mut changes: Vec<KeyChange> = Vec::new();
+    let mut dirty: bool = false;
+
+    for (user_name, (user_id, pub_key)) in users {
+        match status(&pins, user_id, pub_key) {
+            PinStatus::Trusted => {}
+            PinStatus::Unpinned => {
+                if let Ok(fingerprint) = crypto::fingerprint(pub_key) {
+                    log::info!("Pinning first seen key {} for {}", &fingerprint, user_name);
+                    pins.insert(user_id.to_string(), fingerprint);
+                    dirty = true;
+                }
+            }
+            PinStatus::Changed { pinned, presented } => {
+                changes.push(KeyChange {
+                    user_id: user_id.to_string(),
+                    user_name: user_name.to_string(),
+                    pinned,
+                    presented,
+                });
+            }
+        }
+    }
+
+    if dirty {
+        write_pins_file(&pins)?;
+    }
+    Ok(changes)
+}
+
+/// Changed keys found in the local users file that are still awaiting review.
+pub fn pending_changes() -> Vec<KeyChange> {
+    let pins: HashMap<String, String> = read_pins_file();
+    let mut changes: Vec<KeyChange> = Vec::new();
+    for (user_name, (user_id, pub_key)) in io::read_users_file() {
+        if let PinStatus::Changed { pinned, presented } = status(&pins, &user_id, &pub_key) {
+            changes.push(KeyChange {
+                user_id,
+                user_name,
+                pinned,
+                presented,
+            });
+        }
+    }
+    changes
+}
+
+/// Replace the pinned fingerprint of a user after it was verified out-of-band.
+pub fn accept(user_id: &str, fingerprint: &str) -> Result<()> {
+    let mut pins: HashMap<String, String> = read_pins_file();
+    log::warn!("Accepting new key {} for {}", fingerprint, user_id);
+    pins.insert(user_id.to_string(), fingerprint.to_string());
+    write_pins_file(&pins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let pub_key: String = io::load_contents_from_file("./src/test/test.pem.pub").unwrap();
+        let fingerprint: String = crypto::fingerprint(&pub_key).unwrap();
+        let mut pins: HashMap<String, String> = HashMap::new();
+        assert_eq!(status(&pins, "U1234ABC", &pub_key), PinStatus::Unpinned);
+
+        pins.insert("U1234ABC".to_string(), fingerprint.clone());
+        assert_eq!(status(&pins, "U1234ABC", &pub_key), PinStatus::Trusted);
+
+        pins.insert("U1234ABC".to_string(), "00:11".to_string());
+        assert_eq!(
+            status(&pins, "U1234ABC", &pub_key),
+            PinStatus::Changed {
+                pinned: "00:11".to_string(),
+                presented: fingerprint
+            }
+        );
+    }
+}
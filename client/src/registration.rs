@@ -0,0 +1,53 @@
+use std::error::Error;
+
+use crate::directory::DirectoryError;
+use crate::io;
+use crate::prop;
+use crate::util;
+
+/// Submits this client's public key to the server's `POST /api/v1/keys` with the stored `api_token`, instead of
+/// pasting it into Slack. Returns the key's fingerprint, it only becomes active once confirmed in Slack.
+#[tokio::main]
+pub async fn submit_public_key() -> Result<String, Box<dyn Error>> {
+    let base_url: String = prop::get_property("server_base_url", "http://127.0.0.1:8080");
+    let api_token: String = prop::get_property("api_token", "");
+    if api_token.is_empty() {
+        return Err(Box::new(DirectoryError::new(
+            "no API token yet, ask the Slackrypt bot for one with /slackrypt token and paste it in File/Set API Token",
+        )));
+    }
+    let pub_key: String = io::get_public_key_string(&util::default_dir())?;
+
+    let resp = reqwest::Client::new()
+        .post(&(base_url + "/api/v1/keys"))
+        .bearer_auth(&api_token)
+        .json(&serde_json::json!({ "pubkey": pub_key }))
+        .send()
+        .await?;
+    let status: reqwest::StatusCode = resp.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(Box::new(DirectoryError::new(
+            "the server needs a valid API token, ask the Slackrypt bot for one with /slackrypt token and paste it in File/Set API Token",
+        )));
+    }
+    let json_resp: serde_json::Value = resp.json().await.unwrap_or(serde_json::Value::Null);
+    if status != reqwest::StatusCode::ACCEPTED {
+        let reason: String = match json_resp["error"].as_str() {
+            Some(error) => error.to_string(),
+            None => status.to_string(),
+        };
+        return Err(Box::new(DirectoryError::new(&reason)));
+    }
+    Ok(json_resp["fingerprint"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string())
+}
+
+pub fn confirmation_message(fingerprint: &str) -> String {
+    format!(
+        "Your public key (fingerprint {}) was submitted. The Slackrypt bot sent you a code in a DM, \
+         reply to it with `verify <code>` within 10 minutes to activate the key.",
+        fingerprint
+    )
+}
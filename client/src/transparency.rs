@@ -0,0 +1,356 @@
+use std::error::Error;
+use std::vec::Vec;
+
+use rsa::RSAPublicKey;
+use sha2::{Digest, Sha256};
+
+use crate::crypto;
+use crate::directory;
+use crate::directory::DirectoryError;
+use crate::io;
+use crate::prop;
+use crate::util;
+
+/// Client side verification of the server's key transparency log (RFC 9162 section 2.1).
+pub type Hash = [u8; 32];
+
+/// A signed tree head that passed signature and consistency checks.
+#[derive(Debug)]
+pub struct TreeHead {
+    pub tree_size: u64,
+    pub root_hash: Hash,
+}
+
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(&[0x00]);
+    hasher.update(data);
+    to_hash(&hasher.finalize())
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(&[0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    to_hash(&hasher.finalize())
+}
+
+fn to_hash(bytes: &[u8]) -> Hash {
+    let mut hash: Hash = [0; 32];
+    hash.copy_from_slice(bytes);
+    hash
+}
+
+fn decode_hash(hash_b64: &str) -> Result<Hash, DirectoryError> {
+    match base64::decode(hash_b64) {
+        Ok(bytes) if bytes.len() == 32 => Ok(to_hash(&bytes)),
+        _ => Err(DirectoryError::new("malformed hash")),
+    }
+}
+
+fn decode_hashes(hashes: &serde_json::Value) -> Result<Vec<Hash>, DirectoryError> {
+    let hashes: &Vec<serde_json::Value> = hashes
+        .as_array()
+        .ok_or_else(|| DirectoryError::new("malformed proof"))?;
+    hashes
+        .iter()
+        .map(|h| decode_hash(h.as_str().unwrap_or("")))
+        .collect()
+}
+
+/// Right-shift both `first` and `second` until the lowest bit of `first` is set or it is zero.
+fn shift_while_even(first: &mut u64, second: &mut u64) {
+    while *first & 1 == 0 && *first != 0 {
+        *first >>= 1;
+        *second >>= 1;
+    }
+}
+
+pub fn verify_inclusion(
+    leaf_index: u64,
+    tree_size: u64,
+    leaf: &Hash,
+    audit_path: &[Hash],
+    root: &Hash,
+) -> bool {
+    if leaf_index >= tree_size {
+        return false;
+    }
+    let mut f_n: u64 = leaf_index;
+    let mut s_n: u64 = tree_size - 1;
+    let mut r: Hash = *leaf;
+    for p in audit_path {
+        if s_n == 0 {
+            return false;
+        }
+        if f_n & 1 == 1 || f_n == s_n {
+            r = node_hash(p, &r);
+            shift_while_even(&mut f_n, &mut s_n);
+        } else {
+            r = node_hash(&r, p);
+        }
+        f_n >>= 1;
+        s_n >>= 1;
+    }
+    s_n == 0 && &r == root
+}
+
+pub fn verify_consistency(
+    first: u64,
+    second: u64,
+    first_root: &Hash,
+    second_root: &Hash,
+    consistency: &[Hash],
+) -> bool {
+    if first == 0 {
+        return true;
+    }
+    if first == second {
+        return consistency.is_empty() && first_root == second_root;
+    }
+    if first > second || consistency.is_empty() {
+        return false;
+    }
+
+    let mut path: Vec<Hash> = Vec::new();
+    if first.is_power_of_two() {
+        path.push(*first_root);
+    }
+    path.extend_from_slice(consistency);
+
+    let mut f_n: u64 = first - 1;
+    let mut s_n: u64 = second - 1;
+    while f_n & 1 == 1 {
+        f_n >>= 1;
+        s_n >>= 1;
+    }
+    let mut f_r: Hash = path[0];
+    let mut s_r: Hash = path[0];
+    for c in &path[1..] {
+        if s_n == 0 {
+            return false;
+        }
+        if f_n & 1 == 1 || f_n == s_n {
+            f_r = node_hash(c, &f_r);
+            s_r = node_hash(c, &s_r);
+            shift_while_even(&mut f_n, &mut s_n);
+        } else {
+            s_r = node_hash(&s_r, c);
+        }
+        f_n >>= 1;
+        s_n >>= 1;
+    }
+    &f_r == first_root && &s_r == second_root && s_n == 0
+}
+
+/// Sends the stored `api_token`, `/log/entries` needs it like the directory routes.
+async fn get_json(path: &str) -> Result<serde_json::Value, Box<dyn Error>> {
+    let base_url: String = prop::get_property("server_base_url", "http://127.0.0.1:8080");
+    let api_token: String = prop::get_property("api_token", "");
+    let json_resp: serde_json::Value = reqwest::Client::new()
+        .get(&(base_url + path))
+        .bearer_auth(&api_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(json_resp)
+}
+
+/// Fetches the latest signed tree head and checks it is consistent with the last one this client saw.
+async fn get_tree_head() -> Result<TreeHead, Box<dyn Error>> {
+    let server_key: RSAPublicKey = io::get_server_public_key(&util::default_dir())?;
+    let sth: serde_json::Value =
+        directory::verify_signed(&get_json("/log/sth").await?, &server_key)?;
+    let tree_head = TreeHead {
+        tree_size: sth["tree_size"]
+            .as_u64()
+            .ok_or_else(|| DirectoryError::new("missing tree_size"))?,
+        root_hash: decode_hash(sth["root_hash"].as_str().unwrap_or(""))?,
+    };
+
+    let last_size: u64 = prop::get_property("log_tree_size", "0").parse::<u64>()?;
+    if last_size > 0 {
+        if tree_head.tree_size < last_size {
+            return Err(Box::new(DirectoryError::new(&format!(
+                "transparency log shrank from {} to {} entries",
+                last_size, tree_head.tree_size
+            ))));
+        }
+        let last_root: Hash = decode_hash(&prop::get_property("log_root_hash", ""))?;
+        let proof: serde_json::Value = get_json(&format!(
+            "/log/proof/consistency?first={}&second={}",
+            last_size, tree_head.tree_size
+        ))
+        .await?;
+        let consistency: Vec<Hash> = decode_hashes(&proof["consistency"])?;
+        if !verify_consistency(
+            last_size,
+            tree_head.tree_size,
+            &last_root,
+            &tree_head.root_hash,
+            &consistency,
+        ) {
+            return Err(Box::new(DirectoryError::new(
+                "transparency log is not consistent with the last seen tree head",
+            )));
+        }
+    }
+
+    prop::upsert_property("log_tree_size", &tree_head.tree_size.to_string())?;
+    prop::upsert_property("log_root_hash", &util::to_base64_str(&tree_head.root_hash))?;
+    Ok(tree_head)
+}
+
+/// The log entries of a user as `(leaf_index, pubkey)`, oldest first.
+async fn get_log_entries(user_id: &str) -> Result<Vec<(u64, String)>, Box<dyn Error>> {
+    let entries: serde_json::Value = get_json(&format!("/log/entries/{}", user_id)).await?;
+    let mut result: Vec<(u64, String)> = Vec::new();
+    for entry in entries.as_array().unwrap_or(&Vec::new()) {
+        let leaf_index: u64 = entry["leaf_index"]
+            .as_u64()
+            .ok_or_else(|| DirectoryError::new("missing leaf_index"))?;
+        let pubkey: &str = entry["pubkey"]
+            .as_str()
+            .ok_or_else(|| DirectoryError::new("missing pubkey"))?;
+        result.push((leaf_index, pubkey.to_string()));
+    }
+    Ok(result)
+}
+
+async fn verify_entry_included(
+    user_id: &str,
+    leaf_index: u64,
+    pubkey: &str,
+    tree_head: &TreeHead,
+) -> Result<(), Box<dyn Error>> {
+    let proof: serde_json::Value = get_json(&format!(
+        "/log/proof/inclusion?leaf_index={}&tree_size={}",
+        leaf_index, tree_head.tree_size
+    ))
+    .await?;
+    let audit_path: Vec<Hash> = decode_hashes(&proof["audit_path"])?;
+    let leaf: Hash = leaf_hash(format!("{}\n{}", user_id, pubkey).as_bytes());
+    if verify_inclusion(
+        leaf_index,
+        tree_head.tree_size,
+        &leaf,
+        &audit_path,
+        &tree_head.root_hash,
+    ) {
+        Ok(())
+    } else {
+        Err(Box::new(DirectoryError::new(&format!(
+            "key of {} is not included in the transparency log",
+            user_id
+        ))))
+    }
+}
+
+fn is_same_key(a: &str, b: &str) -> bool {
+    match (crypto::fingerprint(a), crypto::fingerprint(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Verifies that the key about to be used for `user_id` is in the transparency log and is their latest entry.
+#[tokio::main]
+pub async fn verify_key(user_id: &str, pub_key: &str) -> Result<(), Box<dyn Error>> {
+    let tree_head: TreeHead = get_tree_head().await?;
+    let entries: Vec<(u64, String)> = get_log_entries(user_id).await?;
+    match entries.last() {
+        Some((leaf_index, pubkey)) if is_same_key(pubkey, pub_key) => {
+            verify_entry_included(user_id, *leaf_index, pubkey, &tree_head).await
+        }
+        _ => Err(Box::new(DirectoryError::new(&format!(
+            "key of {} is not the latest entry in the transparency log",
+            user_id
+        )))),
+    }
+}
+
+/// Audits that the latest logged key of this client's own Slack user is still its own key.
+#[tokio::main]
+pub async fn audit_own_key(user_id: &str, own_pub_key: &str) -> Result<(), Box<dyn Error>> {
+    let tree_head: TreeHead = get_tree_head().await?;
+    let entries: Vec<(u64, String)> = get_log_entries(user_id).await?;
+    for (leaf_index, pubkey) in &entries {
+        verify_entry_included(user_id, *leaf_index, pubkey, &tree_head).await?;
+    }
+    match entries.last() {
+        Some((_, pubkey)) if !is_same_key(pubkey, own_pub_key) => {
+            Err(Box::new(DirectoryError::new(&format!(
+                "the transparency log shows your key ({}) was replaced!",
+                user_id
+            ))))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Generated with the server's `merkle` module over these leaves.
+    fn leaves() -> Vec<Hash> {
+        ["U1\nKEY1", "U1\nKEY2", "U3\nKEY3", "U4\nKEY4", "U5\nKEY5"]
+            .iter()
+            .map(|d| leaf_hash(d.as_bytes()))
+            .collect()
+    }
+
+    fn hashes(hashes_b64: &[&str]) -> Vec<Hash> {
+        hashes_b64.iter().map(|h| decode_hash(h).unwrap()).collect()
+    }
+
+    const ROOT_2: &str = "R81tjcxNpgMciYmIVB0zWLzKzz/4vRE7Mq5lg6QhkLk=";
+    const ROOT_3: &str = "bF4YZM6Hi7DKk6pCGRcOiXHSecBeTQ0I61AsELbfsEY=";
+    const ROOT_5: &str = "b1pDFcWTVGT9CgIl9uUTffbMImK/HHoTLMRik7R285U=";
+
+    #[test]
+    fn test_verify_inclusion() {
+        let root: Hash = decode_hash(ROOT_5).unwrap();
+        let audit_path: Vec<Hash> = hashes(&[
+            "bbVgoHiSD+a279bDHzj9JjAqaa+dqQi06t/JK81L8ro=",
+            "R81tjcxNpgMciYmIVB0zWLzKzz/4vRE7Mq5lg6QhkLk=",
+            "SaohpNfJ8ecBL+XBQNcO1Iy+5f9iTT1hV/QC4OEeykc=",
+        ]);
+        let l: Vec<Hash> = leaves();
+        assert_eq!(verify_inclusion(2, 5, &l[2], &audit_path, &root), true);
+        assert_eq!(verify_inclusion(3, 5, &l[2], &audit_path, &root), false);
+        assert_eq!(verify_inclusion(2, 5, &l[3], &audit_path, &root), false);
+        assert_eq!(verify_inclusion(0, 1, &l[0], &[], &l[0]), true);
+    }
+
+    #[test]
+    fn test_verify_consistency() {
+        let root_2: Hash = decode_hash(ROOT_2).unwrap();
+        let root_3: Hash = decode_hash(ROOT_3).unwrap();
+        let root_5: Hash = decode_hash(ROOT_5).unwrap();
+        let proof_2_5: Vec<Hash> = hashes(&[
+            "I9k/A6EMDi3zY1Wwc42abEAxpEeqWD+Zs+v4WP69C5w=",
+            "SaohpNfJ8ecBL+XBQNcO1Iy+5f9iTT1hV/QC4OEeykc=",
+        ]);
+        let proof_3_5: Vec<Hash> = hashes(&[
+            "cpQXH+F0spyjwFpZ0KJ09Wj4JxQwsJazM1IBSPVayZg=",
+            "bbVgoHiSD+a279bDHzj9JjAqaa+dqQi06t/JK81L8ro=",
+            "R81tjcxNpgMciYmIVB0zWLzKzz/4vRE7Mq5lg6QhkLk=",
+            "SaohpNfJ8ecBL+XBQNcO1Iy+5f9iTT1hV/QC4OEeykc=",
+        ]);
+        assert_eq!(verify_consistency(2, 5, &root_2, &root_5, &proof_2_5), true);
+        assert_eq!(verify_consistency(3, 5, &root_3, &root_5, &proof_3_5), true);
+        assert_eq!(
+            verify_consistency(3, 5, &root_2, &root_5, &proof_3_5),
+            false
+        );
+        assert_eq!(
+            verify_consistency(2, 5, &root_2, &root_3, &proof_2_5),
+            false
+        );
+        assert_eq!(verify_consistency(5, 5, &root_5, &root_5, &[]), true);
+    }
+}
@@ -0,0 +1,317 @@
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::content::Content;
+use rocket::response::status::Custom;
+use rocket::{Outcome, Route};
+use rocket_contrib::json::JsonValue;
+use sha2::{Digest, Sha256};
+
+use crate::api;
+use crate::config;
+use crate::db;
+use crate::db::{AuditEvent, AuditQuery, Key, User};
+use crate::slack_api::SlackApi;
+use crate::slack_events;
+use crate::store;
+use crate::tokens;
+
+/// The most entries one `/audit` request returns.
+const AUDIT_PAGE_LIMIT: usize = 1000;
+
+/// Mounted at `/admin/v1`. Every route needs one of the `SLACKRYPT_ADMIN_TOKENS` and is written to the audit log,
+/// `503 Service Unavailable` when the key directory cannot be read or the audit log written.
+pub fn routes() -> Vec<Route> {
+    routes![
+        users,
+        revoke_key,
+        revoke_tokens,
+        delete_user,
+        sync_users,
+        export,
+        audit,
+        audit_verify,
+        audit_export
+    ]
+}
+
+/// The admin tokens as `name:token` pairs separated by commas or whitespace, e.g. `alice:s3cr3t,deploy:0th3r`.
+/// The name identifies the operator in the audit log.
+pub fn admin_tokens(spec: &str) -> Vec<(&str, &str)> {
+    spec.split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(|pair| pair.split_once(':'))
+        .filter(|(name, token)| !name.is_empty() && !token.is_empty())
+        .collect()
+}
+
+/// The name of the admin token `token` is, comparing hashes so the time taken does not leak the tokens.
+pub fn authenticate<'a>(spec: &'a str, token: &str) -> Option<&'a str> {
+    let presented = Sha256::digest(token.as_bytes());
+    admin_tokens(spec)
+        .into_iter()
+        .find(|(_, t)| Sha256::digest(t.as_bytes()) == presented)
+        .map(|(name, _)| name)
+}
+
+/// An operator authenticated with `Authorization: Bearer <token>`, `401 Unauthorized` otherwise.
+pub struct Admin {
+    pub name: String,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let spec: &str = &config::get().admin_tokens;
+        let token: Option<&str> = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|v| v.strip_prefix("Bearer "));
+        match token.and_then(|t| authenticate(spec, t.trim())) {
+            Some(name) => Outcome::Success(Admin {
+                name: name.to_string(),
+            }),
+            None => {
+                log::warn!("Rejecting admin request to {}", request.uri());
+                Outcome::Failure((Status::Unauthorized, ()))
+            }
+        }
+    }
+}
+
+impl Admin {
+    /// Records that this operator did `action` to the user `subject`, empty for the whole directory.
+    /// The request fails with `503 Service Unavailable` when it cannot be audited.
+    fn audit(
+        &self,
+        action: &str,
+        subject: &str,
+        detail: serde_json::Value,
+    ) -> Result<(), Custom<JsonValue>> {
+        let actor: String = self.actor();
+        log::info!("{} {} {}", actor, action, subject);
+        db::record_audit_event(&actor, action, subject, &detail).map_err(api::store_error)?;
+        Ok(())
+    }
+
+    /// The actor key changes made by this operator are audited as.
+    fn actor(&self) -> String {
+        format!("admin:{}", self.name)
+    }
+}
+
+/// curl -H "Authorization: Bearer s3cr3t" "http://127.0.0.1:8000/admin/v1/users?has_key=false"
+///
+/// Every user with their key status, ordered by name.
+#[get("/users?<has_key>")]
+fn users(admin: Admin, has_key: Option<bool>) -> Result<JsonValue, Custom<JsonValue>> {
+    log::debug!("admin users() entering...");
+    admin.audit(
+        "admin-list-users",
+        "",
+        serde_json::json!({ "has_key": has_key }),
+    )?;
+    let all: Vec<User> = db::get_users().map_err(api::store_error)?;
+    let (users, total): (Vec<User>, usize) = api::page(all, has_key, 0, usize::MAX);
+    let users: Vec<JsonValue> = users.iter().map(api::user_json).collect();
+    Ok(json!({
+        "users": users,
+        "total": total,
+    }))
+}
+
+/// curl -X POST -H "Authorization: Bearer s3cr3t" "http://127.0.0.1:8000/admin/v1/users/U1234ABC/revoke?reason=compromised"
+///
+/// Revokes the user's current key, `404 Not Found` when they have none.
+#[post("/users/<user_id>/revoke?<reason>")]
+fn revoke_key(
+    admin: Admin,
+    user_id: String,
+    reason: Option<String>,
+) -> Result<Option<JsonValue>, Custom<JsonValue>> {
+    log::debug!("admin revoke_key() entering...");
+    let reason: String = reason.unwrap_or_else(|| String::from("revoked by admin"));
+    // audited as key-revoked
+    let revoked: Option<Key> =
+        db::revoke_pubkey(&admin.actor(), &user_id, &reason).map_err(api::store_error)?;
+    Ok(revoked.map(|revoked| {
+        json!({
+            "user_id": user_id,
+            "fingerprint": revoked.fingerprint,
+            "revoked_at": revoked.revoked_at,
+            "revocation_reason": revoked.revocation_reason,
+        })
+    }))
+}
+
+/// curl -X POST -H "Authorization: Bearer s3cr3t" http://127.0.0.1:8000/admin/v1/users/U1234ABC/tokens/revoke
+///
+/// Revokes every API token of the user, e.g. when their laptop was lost.
+#[post("/users/<user_id>/tokens/revoke")]
+fn revoke_tokens(admin: Admin, user_id: String) -> Result<JsonValue, Custom<JsonValue>> {
+    log::debug!("admin revoke_tokens() entering...");
+    // audited as api-tokens-revoked
+    let revoked: usize = tokens::revoke(&admin.actor(), &user_id).map_err(api::store_error)?;
+    Ok(json!({ "user_id": user_id, "revoked": revoked }))
+}
+
+/// curl -X DELETE -H "Authorization: Bearer s3cr3t" http://127.0.0.1:8000/admin/v1/users/U1234ABC
+///
+/// Removes a departed user, revoking their key and API tokens. They stay as a deactivated user without a key so
+/// clients syncing `/changes` drop them, their key history and log entries stay. `404` when already deleted.
+#[delete("/users/<user_id>")]
+fn delete_user(admin: Admin, user_id: String) -> Result<Option<JsonValue>, Custom<JsonValue>> {
+    log::debug!("admin delete_user() entering...");
+    // audited as user-deleted
+    if !db::delete_user(&admin.actor(), &user_id).map_err(api::store_error)? {
+        return Ok(None);
+    }
+    Ok(Some(json!({ "user_id": user_id, "deleted": true })))
+}
+
+/// curl -X POST -H "Authorization: Bearer s3cr3t" http://127.0.0.1:8000/admin/v1/sync
+///
+/// Syncs the workspace members from Slack now, `502 Bad Gateway` when Slack cannot be reached and
+/// `503 Service Unavailable` when the members cannot be stored.
+#[post("/sync")]
+fn sync_users(admin: Admin) -> Result<JsonValue, Custom<JsonValue>> {
+    log::debug!("admin sync_users() entering...");
+    match slack_events::sync_members(&SlackApi::new(&config::get().slack.bot_token)) {
+        Ok(members) => {
+            admin.audit(
+                "admin-sync-users",
+                "",
+                serde_json::json!({ "members": members }),
+            )?;
+            Ok(json!({
+                "members": members,
+                "revision": db::get_directory_serial().map_err(api::store_error)?,
+            }))
+        }
+        Err(e) => {
+            log::error!("Admin user sync failed: {}", e);
+            let status: Status = match e {
+                slack_events::SyncError::Slack(_) => Status::BadGateway,
+                slack_events::SyncError::Store(_) => Status::ServiceUnavailable,
+            };
+            Err(Custom(status, json!({ "error": e.to_string() })))
+        }
+    }
+}
+
+/// curl -H "Authorization: Bearer s3cr3t" http://127.0.0.1:8000/admin/v1/export > slackrypt-export.json
+///
+/// Every user with their whole key history, see `db::export`.
+#[get("/export")]
+fn export(admin: Admin) -> Result<JsonValue, Custom<JsonValue>> {
+    log::debug!("admin export() entering...");
+    let export: serde_json::Value = db::export().map_err(api::store_error)?;
+    admin.audit(
+        "admin-export",
+        "",
+        serde_json::json!({ "serial": export["serial"] }),
+    )?;
+    Ok(JsonValue(export))
+}
+
+/// curl -H "Authorization: Bearer s3cr3t" "http://127.0.0.1:8000/admin/v1/audit?subject=U1234ABC&after=0&limit=100"
+///
+/// Audit log entries oldest first, filtered by `subject`, `actor`, `action` and `since` (a unix timestamp).
+/// Page through the log with `after`, the last id seen. `limit` defaults to and is capped at 1000.
+#[get("/audit?<subject>&<actor>&<action>&<since>&<after>&<limit>")]
+fn audit(
+    admin: Admin,
+    subject: Option<String>,
+    actor: Option<String>,
+    action: Option<String>,
+    since: Option<i64>,
+    after: Option<i64>,
+    limit: Option<usize>,
+) -> Result<JsonValue, Custom<JsonValue>> {
+    log::debug!("admin audit() entering...");
+    let query = AuditQuery {
+        subject,
+        actor,
+        action,
+        since,
+        after_id: after,
+        limit: Some(limit.unwrap_or(AUDIT_PAGE_LIMIT).min(AUDIT_PAGE_LIMIT)),
+    };
+    admin.audit(
+        "admin-audit-query",
+        "",
+        serde_json::json!({
+            "subject": query.subject,
+            "actor": query.actor,
+            "action": query.action,
+            "since": query.since,
+            "after": query.after_id,
+        }),
+    )?;
+    let events: Vec<serde_json::Value> = db::get_audit_events(&query)
+        .map_err(api::store_error)?
+        .iter()
+        .map(db::audit_event_json)
+        .collect();
+    Ok(json!({ "events": events }))
+}
+
+/// curl -H "Authorization: Bearer s3cr3t" http://127.0.0.1:8000/admin/v1/audit/verify
+///
+/// Checks the hash chain of the whole audit log, `409 Conflict` naming the first bad entry when it was tampered with.
+#[get("/audit/verify")]
+fn audit_verify(admin: Admin) -> Result<JsonValue, Custom<JsonValue>> {
+    log::debug!("admin audit_verify() entering...");
+    admin.audit("admin-audit-verify", "", serde_json::json!({}))?;
+    let events: Vec<AuditEvent> =
+        db::get_audit_events(&AuditQuery::default()).map_err(api::store_error)?;
+    match store::verify_audit_chain(&events) {
+        Ok(entries) => Ok(json!({ "valid": true, "entries": entries })),
+        Err(e) => {
+            log::error!("Audit log verification failed: {}", e);
+            Err(Custom(
+                Status::Conflict,
+                json!({ "valid": false, "error": e }),
+            ))
+        }
+    }
+}
+
+/// curl -H "Authorization: Bearer s3cr3t" "http://127.0.0.1:8000/admin/v1/audit/export?after=0" > audit.jsonl
+///
+/// The audit log after the id `after` as JSON lines for a SIEM, one `/audit` event per line.
+#[get("/audit/export?<after>")]
+fn audit_export(admin: Admin, after: Option<i64>) -> Result<Content<String>, Custom<JsonValue>> {
+    log::debug!("admin audit_export() entering...");
+    admin.audit(
+        "admin-audit-export",
+        "",
+        serde_json::json!({ "after": after }),
+    )?;
+    let query = AuditQuery {
+        after_id: after,
+        ..AuditQuery::default()
+    };
+    Ok(Content(
+        ContentType::new("application", "x-ndjson"),
+        db::audit_jsonl(&query).map_err(api::store_error)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authenticate() {
+        let spec: &str = "alice:s3cr3t, deploy:0th:3r\nbroken :nothing";
+        assert_eq!(
+            admin_tokens(spec),
+            vec![("alice", "s3cr3t"), ("deploy", "0th:3r")]
+        );
+        assert_eq!(authenticate(spec, "s3cr3t"), Some("alice"));
+        assert_eq!(authenticate(spec, "0th:3r"), Some("deploy"));
+        assert_eq!(authenticate(spec, "s3cr3"), None);
+        assert_eq!(authenticate(spec, ""), None);
+        assert_eq!(authenticate("", "s3cr3t"), None);
+    }
+}
@@ -0,0 +1,241 @@
+use rocket::http::Status;
+use rocket::response::status::Custom;
+use rocket::Route;
+use rocket_contrib::json::{Json, JsonValue};
+use serde::Deserialize;
+
+use crate::bot;
+use crate::challenge::{Confirmation, CHALLENGE_TTL_SECS};
+use crate::config;
+use crate::crypto;
+use crate::db;
+use crate::db::User;
+use crate::etag::{self, IfNoneMatch, Tagged};
+use crate::ratelimit::{self, DirectoryLimit, HttpLimit};
+use crate::slack_api::SlackApi;
+use crate::store::StoreError;
+use crate::tokens::ApiUser;
+use crate::util;
+
+/// Users per page when `limit` is not given, and the most a client may ask for.
+pub const DEFAULT_LIMIT: usize = 100;
+pub const MAX_LIMIT: usize = 1000;
+
+/// Mounted at `/api/v1`. Unlike `/pubkey/users` every user is a JSON object and an empty directory is an empty list.
+/// Every route needs an API token the bot issued, sent as `Authorization: Bearer <token>`, and answers
+/// `503 Service Unavailable` when the key directory cannot be read.
+pub fn routes() -> Vec<Route> {
+    routes![users, user, handle, submit_key]
+}
+
+#[derive(Deserialize)]
+pub struct KeySubmission {
+    pub pubkey: String,
+}
+
+pub fn user_json(user: &User) -> JsonValue {
+    let pem: Option<&str> = if user.pubkey.is_empty() {
+        None
+    } else {
+        Some(&user.pubkey)
+    };
+    json!({
+        "id": user.user_id,
+        "name": user.name,
+        "real_name": user.real_name,
+        "deleted": user.deleted,
+        "fingerprint": user.fingerprint,
+        "pem": pem,
+        "updated_at": user.updated_at,
+        "revision": user.revision,
+    })
+}
+
+/// Keeps the users matching `has_key`, then returns the requested page and how many users matched.
+pub fn page(
+    users: Vec<User>,
+    has_key: Option<bool>,
+    offset: usize,
+    limit: usize,
+) -> (Vec<User>, usize) {
+    let matching: Vec<User> = users
+        .into_iter()
+        .filter(|u| match has_key {
+            Some(has_key) => has_key != u.pubkey.is_empty(),
+            None => true,
+        })
+        .collect();
+    let total: usize = matching.len();
+    let page: Vec<User> = matching.into_iter().skip(offset).take(limit).collect();
+    (page, total)
+}
+
+/// curl -H "Content-Type: application/json" -H "Authorization: Bearer slackrypt_..." "http://127.0.0.1:8000/api/v1/users?has_key=true&offset=0&limit=100"
+///
+/// Users ordered by name. `total` counts every user matching the filter, not just this page.
+#[get("/users?<has_key>&<offset>&<limit>")]
+fn users(
+    _limit: DirectoryLimit,
+    _user: ApiUser,
+    has_key: Option<bool>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    if_none_match: IfNoneMatch,
+) -> Result<Tagged<JsonValue>, Custom<JsonValue>> {
+    log::debug!("api users() entering...");
+    let offset: usize = offset.unwrap_or(0);
+    let limit: usize = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let serial: i64 = db::get_directory_serial().map_err(store_error)?;
+    etag::tagged(&if_none_match, serial, || {
+        let all: Vec<User> = db::get_users().map_err(store_error)?;
+        let (users, total): (Vec<User>, usize) = page(all, has_key, offset, limit);
+        let users: Vec<JsonValue> = users.iter().map(user_json).collect();
+        Ok(json!({
+            "users": users,
+            "total": total,
+            "offset": offset,
+            "limit": limit,
+            "revision": serial,
+        }))
+    })
+}
+
+/// curl -H "Content-Type: application/json" -H "Authorization: Bearer slackrypt_..." http://127.0.0.1:8000/api/v1/users/U1234ABC
+#[get("/users/<user_id>")]
+fn user(
+    _limit: HttpLimit,
+    _user: ApiUser,
+    user_id: String,
+) -> Result<Option<JsonValue>, Custom<JsonValue>> {
+    log::debug!("api user() entering...");
+    let user: Option<User> = db::get_user(&user_id).map_err(store_error)?;
+    Ok(user.map(|u| user_json(&u)))
+}
+
+/// curl -H "Content-Type: application/json" -H "Authorization: Bearer slackrypt_..." http://127.0.0.1:8000/api/v1/handles/jeff
+///
+/// Looks a user up by Slack handle instead of user id.
+#[get("/handles/<name>")]
+fn handle(
+    _limit: HttpLimit,
+    _user: ApiUser,
+    name: String,
+) -> Result<Option<JsonValue>, Custom<JsonValue>> {
+    log::debug!("api handle() entering...");
+    let user: Option<User> = db::get_user_by_name(&name).map_err(store_error)?;
+    Ok(user.map(|u| user_json(&u)))
+}
+
+/// curl -X POST -H "Content-Type: application/json" -H "Authorization: Bearer slackrypt_..." -d '{"pubkey": "-----BEGIN PUBLIC KEY-----\n..."}' http://127.0.0.1:8000/api/v1/keys
+///
+/// Submits a public key for the token's user, without pasting it into Slack. The bot DMs them a one-time code and the
+/// key only becomes theirs once they reply with `verify <code>`, until then it is `202 Accepted`. Unlike a pasted key
+/// this proves the user approved the key, not that they hold its private key, see `bot::submit_key`.
+/// `422 Unprocessable Entity` for a key that is not an RSA key of at least 2048 bits, `429` over
+/// `key_submissions_per_hour`, `502 Bad Gateway` when the code cannot be DMed and `503 Service Unavailable` when the
+/// key cannot be stored.
+#[post("/keys", format = "json", data = "<submission>")]
+fn submit_key(
+    _limit: HttpLimit,
+    api_user: ApiUser,
+    submission: Json<KeySubmission>,
+) -> Custom<JsonValue> {
+    log::debug!("api submit_key() entering...");
+    let user: &User = &api_user.user;
+    if let Err(wait) = ratelimit::check_key_submission(&user.user_id) {
+        return Custom(
+            Status::TooManyRequests,
+            json!({ "error": ratelimit::slow_down(wait) }),
+        );
+    }
+    let pubkey: &str = submission.pubkey.trim();
+    let confirmation: Confirmation = match Confirmation::create(pubkey) {
+        Ok(confirmation) => confirmation,
+        Err(e) => return Custom(Status::UnprocessableEntity, json!({ "error": e })),
+    };
+    if let Err(e) = bot::submit_key(user, pubkey, &confirmation) {
+        log::error!(
+            "Could not store the key submitted by {}: {}",
+            user.user_id,
+            e
+        );
+        return unavailable();
+    }
+    let fingerprint: String = crypto::fingerprint(pubkey).unwrap_or_default();
+    let api: SlackApi = SlackApi::new(&config::get().slack.bot_token);
+    if let Err(e) = api.post_message(&user.user_id, &confirmation.instructions(&fingerprint)) {
+        log::error!(
+            "Could not DM a key confirmation code to {}: {}",
+            user.user_id,
+            e
+        );
+        if let Err(e) = db::delete_pending_key(&user.user_id) {
+            log::error!("Could not drop the pending key of {}: {}", user.user_id, e);
+            return unavailable();
+        }
+        return Custom(
+            Status::BadGateway,
+            json!({ "error": "the confirmation code could not be sent, please try again later" }),
+        );
+    }
+    Custom(
+        Status::Accepted,
+        json!({
+            "user_id": user.user_id,
+            "fingerprint": fingerprint,
+            "expires_at": util::unix_timestamp() + CHALLENGE_TTL_SECS,
+        }),
+    )
+}
+
+/// `503 Service Unavailable` when the key directory cannot be read or written.
+fn unavailable() -> Custom<JsonValue> {
+    Custom(
+        Status::ServiceUnavailable,
+        json!({ "error": "the key directory is unavailable, please try again later" }),
+    )
+}
+
+/// Logs `e` and answers `503 Service Unavailable`, for `map_err` on store calls.
+pub fn store_error(e: StoreError) -> Custom<JsonValue> {
+    log::error!("Key directory unavailable: {}", e);
+    unavailable()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(user_id: &str, pubkey: &str) -> User {
+        User {
+            user_id: user_id.to_string(),
+            name: user_id.to_lowercase(),
+            real_name: String::new(),
+            deleted: false,
+            pubkey: pubkey.to_string(),
+            fingerprint: None,
+            updated_at: None,
+            revision: 0,
+        }
+    }
+
+    #[test]
+    fn test_page() {
+        let users: Vec<User> = vec![user("U1", "KEY1"), user("U2", ""), user("U3", "KEY3")];
+
+        let (all, total) = page(users.clone(), None, 0, DEFAULT_LIMIT);
+        assert_eq!((all.len(), total), (3, 3));
+
+        let (with_key, total) = page(users.clone(), Some(true), 1, 1);
+        assert_eq!(total, 2);
+        assert_eq!(with_key[0].user_id, "U3");
+
+        let (without_key, total) = page(users.clone(), Some(false), 0, DEFAULT_LIMIT);
+        assert_eq!(total, 1);
+        assert_eq!(without_key[0].user_id, "U2");
+
+        let (past_end, total) = page(users, None, 10, DEFAULT_LIMIT);
+        assert!(past_end.is_empty());
+        assert_eq!(total, 3);
+    }
+}
@@ -0,0 +1,317 @@
+use log::debug;
+
+use crate::challenge;
+use crate::challenge::{Challenge, Confirmation};
+use crate::commands;
+use crate::crypto;
+use crate::db;
+use crate::db::{Member, User};
+use crate::ratelimit;
+use crate::slack_api::SlackApi;
+use crate::store::StoreResult;
+use crate::util;
+
+/// A message the bot received, whichever Slack API delivered it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Incoming {
+    pub sender: String,
+    pub channel_id: String,
+    pub text: String,
+    /// Sent in a DM with the bot.
+    pub direct: bool,
+}
+
+/// The bot's commands, shared by the Events API receiver and the legacy RTM client.
+pub struct Bot {
+    pub server_base_url: String,
+    pub user_id: String,
+    pub api: SlackApi,
+}
+
+impl Bot {
+    pub fn new(server_base_url: &str, user_id: &str, api: SlackApi) -> Bot {
+        Bot {
+            server_base_url: server_base_url.to_string(),
+            user_id: user_id.to_string(),
+            api,
+        }
+    }
+
+    /// The String pattern to look for when responding to @Slackrypt <command>
+    fn reply_pattern(&self) -> String {
+        "<@".to_string() + &self.user_id + "> "
+    }
+
+    fn should_reply(&self, event_text: &str) -> bool {
+        event_text.starts_with(&self.reply_pattern())
+    }
+
+    fn is_public_key(&self, msg: &Incoming) -> bool {
+        let text: &str = msg.text.trim();
+        msg.direct
+            && text.starts_with("-----BEGIN PUBLIC KEY-----")
+            && text.ends_with("-----END PUBLIC KEY-----")
+    }
+
+    /// `verify <output>` in a DM, unlike the `verify @user` command.
+    fn is_challenge_answer(&self, msg: &Incoming) -> bool {
+        let text: &str = msg.text.trim();
+        msg.direct
+            && text.starts_with("verify ")
+            && !text
+                .trim_start_matches("verify ")
+                .trim_start()
+                .starts_with(['<', '@'])
+    }
+
+    /// A DM starting with a command, e.g. `whois @jeff`.
+    fn is_direct_command(&self, msg: &Incoming) -> bool {
+        let name: &str = msg.text.split_whitespace().next().unwrap_or("");
+        msg.direct && commands::find(name).is_some()
+    }
+
+    /// The reply to `msg`, `None` when it is not meant for the bot.
+    pub fn handle(&self, msg: &Incoming) -> Option<String> {
+        let event_text: &str = msg.text.trim();
+        if (event_text == "init" || event_text == "help") && msg.direct {
+            Some(self.init_instructions())
+        } else if self.is_public_key(msg) {
+            Some(available(self.register(&msg.sender, event_text)))
+        } else if self.is_challenge_answer(msg) {
+            Some(available(self.verify(
+                &msg.sender,
+                event_text.trim_start_matches("verify "),
+            )))
+        } else if self.should_reply(&msg.text) {
+            let args: &str = &msg.text[self.reply_pattern().len()..];
+            Some(self.command(&msg.sender, args))
+        } else if self.is_direct_command(msg) {
+            Some(self.command(&msg.sender, event_text))
+        } else {
+            None
+        }
+    }
+
+    /// Runs a command of `sender`, e.g. `key @jeff`, given as `/slackrypt` text, after mentioning the bot or in a DM.
+    /// A sender over `bot_commands_per_minute` is asked to slow down instead.
+    pub fn command(&self, sender: &str, text: &str) -> String {
+        if let Err(wait) = ratelimit::check_bot_command(sender) {
+            return ratelimit::slow_down(wait);
+        }
+        commands::run(self, sender, text)
+    }
+
+    pub fn init_instructions(&self) -> String {
+        let mut response: String = format!(
+            "Run this in your terminal: `curl -sSf https://{}/init.sh | sh`",
+            &self.server_base_url
+        );
+        response.push_str(
+            "\n\nThe client needs an API token to register your key and download public keys, ask me for one with `token`.",
+        );
+        response.push_str(
+            "\n\nThen register your key with File/Register Public Key in the client, \
+             or paste your public key found at `~/.slackrypt/key.pem.pub` here.",
+        );
+        response
+    }
+
+    /// Stores a member who joined, changed their profile or was deactivated.
+    pub fn sync_member(&self, member: Member) {
+        debug!("Syncing member {} ({})", member.user_id, member.name);
+        let user_id: String = member.user_id.clone();
+        if let Err(e) = db::upsert_members(&[member]) {
+            log::error!("Could not store member {}: {}", user_id, e);
+        }
+    }
+
+    /// The user from the directory. One missing from it, e.g. because a `team_join` event was lost,
+    /// is looked up with `users.info` and stored.
+    pub fn find_user(&self, user_id: &str) -> StoreResult<Option<User>> {
+        if let Some(user) = db::get_user(user_id)? {
+            return Ok(Some(user));
+        }
+        match self.api.users_info(user_id) {
+            Ok(Some(member)) => {
+                db::upsert_members(&[member])?;
+                db::get_user(user_id)
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                log::warn!("Could not look up user {}: {}", user_id, e);
+                Ok(None)
+            }
+        }
+    }
+
+    fn user_name(&self, user_id: &str) -> StoreResult<String> {
+        Ok(match self.find_user(user_id)? {
+            Some(user) => user.name,
+            None => user_id.to_string(),
+        })
+    }
+
+    /// The key only becomes active once the sender proves they hold its private key.
+    fn register(&self, sender: &str, pubkey: &str) -> StoreResult<String> {
+        if let Err(wait) = ratelimit::check_key_submission(sender) {
+            return Ok(ratelimit::slow_down(wait));
+        }
+        Ok(match Challenge::create(pubkey) {
+            Ok(challenge) => {
+                db::upsert_pending_key(
+                    sender,
+                    &self.user_name(sender)?,
+                    pubkey,
+                    &challenge.nonce_hash(),
+                )?;
+                db::record_audit_event(
+                    sender,
+                    "key-submitted",
+                    sender,
+                    &serde_json::json!({ "fingerprint": crypto::fingerprint(pubkey) }),
+                )?;
+                challenge.instructions()
+            }
+            Err(e) => format!("Sorry, I cannot accept that public key: {}", e),
+        })
+    }
+
+    /// Registers the pending key of `sender` when `answer` is its challenge nonce or confirmation code.
+    /// The pending key is dropped before the key is stored, so an answer is never accepted twice.
+    pub fn verify(&self, sender: &str, answer: &str) -> StoreResult<String> {
+        if let Err(wait) = ratelimit::check_key_submission(sender) {
+            return Ok(ratelimit::slow_down(wait));
+        }
+        Ok(match db::select_pending_key(sender)? {
+            Some(pending) if challenge::is_answer(&pending, answer, util::unix_timestamp()) => {
+                db::delete_pending_key(sender)?;
+                db::upsert_pubkey(sender, &pending.user_id, &pending.name, &pending.pubkey)?;
+                format!("Thank you. If you're curious, your Slack id is {}", sender)
+            }
+            Some(_) => String::from(
+                "That answer is wrong or the challenge expired. Please check the output or paste your public key again.",
+            ),
+            None => String::from("I have no public key waiting for verification from you. Please paste your public key first."),
+        })
+    }
+}
+
+/// The reply, or that the key directory is unavailable when it could not be read or written.
+fn available(reply: StoreResult<String>) -> String {
+    reply.unwrap_or_else(|e| {
+        log::error!("Could not answer a key registration: {}", e);
+        String::from(commands::UNAVAILABLE)
+    })
+}
+
+/// Stores a key `user` submitted over `POST /api/v1/keys` and audits it. Like a pasted key it stays pending until
+/// they reply with `verify <code>`, the caller DMs them `confirmation`, created for the already validated `pubkey`.
+/// There is no proof of possession: the code only shows the user approved the key, not that they hold its private
+/// key. Accepted because a wrong key only locks its own user out of messages sent to them.
+pub fn submit_key(user: &User, pubkey: &str, confirmation: &Confirmation) -> StoreResult<()> {
+    db::upsert_pending_key(&user.user_id, &user.name, pubkey, &confirmation.code_hash())?;
+    db::record_audit_event(
+        &user.user_id,
+        "key-submitted",
+        &user.user_id,
+        &serde_json::json!({ "fingerprint": crypto::fingerprint(pubkey), "source": "api" }),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn incoming(text: &str, direct: bool) -> Incoming {
+        Incoming {
+            sender: "U1234ABC".to_string(),
+            channel_id: "D024BE91L".to_string(),
+            text: text.to_string(),
+            direct,
+        }
+    }
+
+    #[test]
+    fn test_handle() {
+        db::init_memory();
+        let bot: Bot = Bot::new("example.com", "U0LAN0Z89", SlackApi::new(""));
+        let init: String = bot.handle(&incoming("init", true)).unwrap();
+        assert!(init.starts_with(
+            "Run this in your terminal: `curl -sSf https://example.com/init.sh | sh`"
+        ));
+        assert_eq!(bot.handle(&incoming("init", false)), None);
+        assert!(bot
+            .handle(&incoming("<@U0LAN0Z89> help", false))
+            .unwrap()
+            .starts_with("Commands, as `/slackrypt <command>` or by mentioning me:"));
+        assert_eq!(
+            bot.handle(&incoming("<@U0LAN0Z89> dance", false)).unwrap(),
+            "I haven't learned how to execute 'dance' yet. Try `help`."
+        );
+        assert_eq!(bot.handle(&incoming("<@U999> help", false)), None);
+        assert_eq!(bot.handle(&incoming("hello", true)), None);
+        assert_eq!(
+            bot.handle(&incoming("whois", true)).unwrap(),
+            "Usage: `whois @user`"
+        );
+        assert!(bot.is_challenge_answer(&incoming("verify c2xhY2tyeXB0", true)));
+        assert!(!bot.is_challenge_answer(&incoming("verify <@U1234ABC>", true)));
+        assert!(!bot.is_challenge_answer(&incoming("verify @jeff", true)));
+    }
+
+    #[test]
+    fn test_submit_key() {
+        db::init_memory();
+        let bot: Bot = Bot::new("example.com", "U0LAN0Z89", SlackApi::new(""));
+        db::upsert_members(&[Member::new("USUB0001", "submitter", "")]).unwrap();
+        let user: User = db::get_user("USUB0001").unwrap().unwrap();
+        let pubkey: &str = include_str!("../fixtures/keys/rsa2048.pem.pub").trim();
+        let confirmation: Confirmation = Confirmation::create(pubkey).unwrap();
+        submit_key(&user, pubkey, &confirmation).unwrap();
+        assert!(confirmation
+            .instructions(&crypto::fingerprint(pubkey).unwrap())
+            .contains(&format!("`verify {}`", confirmation.code)));
+        // not active before the user confirms it
+        assert!(db::get_user("USUB0001").unwrap().unwrap().pubkey.is_empty());
+
+        let reply = |text: &str| -> String {
+            bot.handle(&Incoming {
+                sender: "USUB0001".to_string(),
+                ..incoming(text, true)
+            })
+            .unwrap()
+        };
+        assert!(reply("verify 1234").starts_with("That answer is wrong"));
+        assert!(reply(&format!("verify {}", confirmation.code)).starts_with("Thank you."));
+        assert_eq!(db::get_user("USUB0001").unwrap().unwrap().pubkey, pubkey);
+        assert!(db::select_pending_key("USUB0001").unwrap().is_none());
+        assert!(reply(&format!("verify {}", confirmation.code))
+            .starts_with("I have no public key waiting for verification from you."));
+    }
+
+    #[test]
+    fn test_key_submission_back_off() {
+        db::init_memory();
+        let bot: Bot = Bot::new("example.com", "U0LAN0Z89", SlackApi::new(""));
+        let paste = Incoming {
+            sender: "URATE0001".to_string(),
+            ..incoming(
+                "-----BEGIN PUBLIC KEY-----\nnot a key\n-----END PUBLIC KEY-----",
+                true,
+            )
+        };
+        let limit: u32 = config::get().rate_limit.key_submissions_per_hour;
+        for _ in 0..limit {
+            assert!(bot
+                .handle(&paste)
+                .unwrap()
+                .starts_with("Sorry, I cannot accept that public key"));
+        }
+        assert!(bot
+            .handle(&paste)
+            .unwrap()
+            .starts_with("You're going too fast, please wait"));
+    }
+}
@@ -0,0 +1,129 @@
+use rand::Rng;
+use rsa::RsaPublicKey;
+
+use crate::crypto;
+use crate::store::PendingKey;
+
+/// How long a submitted key waits for the proof-of-possession answer or confirmation code before it has to be
+/// submitted again.
+pub const CHALLENGE_TTL_SECS: i64 = 600;
+
+/// A random nonce encrypted to a submitted public key. Only the holder of the private key can answer with `nonce`.
+#[derive(Debug)]
+pub struct Challenge {
+    pub nonce: String,
+    pub encrypted_nonce: String,
+}
+
+impl Challenge {
+    pub fn create(pubkey: &str) -> Result<Challenge, String> {
+        let public_key: RsaPublicKey = crypto::validate_public_key(pubkey, crypto::MIN_KEY_BITS)?;
+        let bytes: [u8; 16] = rand::thread_rng().gen();
+        let nonce: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let encrypted_nonce: String = crypto::encrypt_to(&public_key, nonce.as_bytes());
+        Ok(Challenge {
+            nonce,
+            encrypted_nonce,
+        })
+    }
+
+    pub fn nonce_hash(&self) -> String {
+        crypto::sha256_hex(self.nonce.as_bytes())
+    }
+
+    pub fn instructions(&self) -> String {
+        format!(
+            "Before I store your key, please prove you hold its private key. Run this in your terminal:\n\
+             `echo '{}' | base64 -d | openssl pkeyutl -decrypt -inkey ~/.slackrypt/key.pem`\n\
+             and reply with `verify <output>` within {} minutes.",
+            self.encrypted_nonce,
+            CHALLENGE_TTL_SECS / 60
+        )
+    }
+}
+
+/// A one-time code the bot DMs to the user a key was submitted for over `POST /api/v1/keys`. Replying with it
+/// approves the key, so a leaked API token alone cannot register a key for its user. Unlike a `Challenge` it does not
+/// prove possession of the private key.
+#[derive(Debug)]
+pub struct Confirmation {
+    pub code: String,
+}
+
+impl Confirmation {
+    pub fn create(pubkey: &str) -> Result<Confirmation, String> {
+        crypto::validate_public_key(pubkey, crypto::MIN_KEY_BITS)?;
+        let code: u32 = rand::thread_rng().gen_range(0..100_000_000);
+        Ok(Confirmation {
+            code: format!("{:08}", code),
+        })
+    }
+
+    pub fn code_hash(&self) -> String {
+        crypto::sha256_hex(self.code.as_bytes())
+    }
+
+    pub fn instructions(&self, fingerprint: &str) -> String {
+        format!(
+            "A public key with the fingerprint `{}` was submitted for you from the Slackrypt client. \
+             If that was you, reply with `verify {}` within {} minutes to activate it. \
+             If it wasn't, ignore this message and revoke your API tokens with `token revoke`.",
+            fingerprint,
+            self.code,
+            CHALLENGE_TTL_SECS / 60
+        )
+    }
+}
+
+/// Whether `answer` is the nonce of a challenge or the code of a confirmation, and still in time.
+pub fn is_answer(pending: &PendingKey, answer: &str, now: i64) -> bool {
+    now - pending.created_at <= CHALLENGE_TTL_SECS
+        && crypto::sha256_hex(answer.trim().as_bytes()) == pending.nonce_hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_answer() {
+        let pending = PendingKey {
+            user_id: "U1234ABC".to_string(),
+            name: "jeff".to_string(),
+            pubkey: String::new(),
+            nonce_hash: crypto::sha256_hex(b"00112233445566778899aabbccddeeff"),
+            created_at: 1_600_000_000,
+        };
+        assert!(is_answer(
+            &pending,
+            " 00112233445566778899aabbccddeeff\n",
+            1_600_000_000
+        ));
+        assert!(!is_answer(&pending, "00112233", 1_600_000_000));
+        assert!(!is_answer(
+            &pending,
+            "00112233445566778899aabbccddeeff",
+            1_600_000_000 + CHALLENGE_TTL_SECS + 1
+        ));
+    }
+
+    #[test]
+    fn test_confirmation() {
+        let pubkey: &str = include_str!("../fixtures/keys/rsa2048.pem.pub");
+        let confirmation: Confirmation = Confirmation::create(pubkey).unwrap();
+        assert_eq!(confirmation.code.len(), 8);
+        assert!(confirmation.code.chars().all(|c| c.is_ascii_digit()));
+        let pending = PendingKey {
+            user_id: "U1234ABC".to_string(),
+            name: "jeff".to_string(),
+            pubkey: pubkey.to_string(),
+            nonce_hash: confirmation.code_hash(),
+            created_at: 1_600_000_000,
+        };
+        assert!(is_answer(&pending, &confirmation.code, 1_600_000_000));
+        assert!(
+            Confirmation::create("-----BEGIN PUBLIC KEY-----\nfoo\n-----END PUBLIC KEY-----")
+                .is_err()
+        );
+    }
+}
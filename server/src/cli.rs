@@ -0,0 +1,257 @@
+use std::fs;
+use std::vec::Vec;
+
+use crate::db;
+use crate::db::{Key, User};
+use crate::tokens;
+use crate::util;
+
+pub const USAGE: &str = "Usage: slackrypt-server [command]
+Commands:
+  serve                          run the bot and the HTTP server (the default)
+  migrate                        apply pending database migrations and exit
+  users list                     every user with their key status
+  users show <user_id>           a user and their key history
+  keys revoke <user_id> [reason] revoke a user's current key
+  tokens revoke <user_id>        revoke every API token of a user
+  export [file]                  every user and their key history as JSON, to stdout without a file
+  import <file>                  load an export into an empty database
+  backup <path>                  copy the SQLite database to a new file while the server keeps running
+  audit export [file]            the audit log as JSON lines, to stdout without a file
+  audit verify                   check the hash chain of the audit log
+  help                           this text
+The database is `database_url` from the config file (SLACKRYPT_CONFIG, ~/.slackrypt-server/config.toml by default),
+overridden by SLACKRYPT_DATABASE_URL, as for serve.";
+
+/// What `slackrypt-server` was asked to do on the command line.
+#[derive(Debug, PartialEq)]
+pub enum Subcommand {
+    Serve,
+    Migrate,
+    UsersList,
+    UsersShow(String),
+    KeysRevoke(String, String),
+    TokensRevoke(String),
+    Export(Option<String>),
+    Import(String),
+    Backup(String),
+    AuditExport(Option<String>),
+    AuditVerify,
+    Help,
+}
+
+/// Parses the arguments after the program name, no arguments serve as before subcommands existed.
+pub fn parse(args: &[String]) -> Result<Subcommand, String> {
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    match args.as_slice() {
+        [] | ["serve"] => Ok(Subcommand::Serve),
+        ["migrate"] => Ok(Subcommand::Migrate),
+        ["users", "list"] => Ok(Subcommand::UsersList),
+        ["users", "show", user_id] => Ok(Subcommand::UsersShow(user_id.to_string())),
+        ["keys", "revoke", user_id] => Ok(Subcommand::KeysRevoke(
+            user_id.to_string(),
+            String::from("revoked by admin"),
+        )),
+        ["keys", "revoke", user_id, reason @ ..] => Ok(Subcommand::KeysRevoke(
+            user_id.to_string(),
+            reason.join(" "),
+        )),
+        ["tokens", "revoke", user_id] => Ok(Subcommand::TokensRevoke(user_id.to_string())),
+        ["export"] => Ok(Subcommand::Export(None)),
+        ["export", file] => Ok(Subcommand::Export(Some(file.to_string()))),
+        ["import", file] => Ok(Subcommand::Import(file.to_string())),
+        ["backup", path] => Ok(Subcommand::Backup(path.to_string())),
+        ["audit", "export"] => Ok(Subcommand::AuditExport(None)),
+        ["audit", "export", file] => Ok(Subcommand::AuditExport(Some(file.to_string()))),
+        ["audit", "verify"] => Ok(Subcommand::AuditVerify),
+        ["help"] | ["-h"] | ["--help"] => Ok(Subcommand::Help),
+        _ => Err(format!("Unknown command '{}'\n{}", args.join(" "), USAGE)),
+    }
+}
+
+/// The operator in the audit log, commands run from a shell have no token to name them.
+fn actor() -> String {
+    format!("cli:{}", util::get_env_var("USER", "unknown"))
+}
+
+fn audit(action: &str, subject: &str, detail: serde_json::Value) -> Result<(), String> {
+    db::record_audit_event(&actor(), action, subject, &detail).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn user_line(user: &User) -> String {
+    format!(
+        "{}\t{}\t{}\t{}",
+        user.user_id,
+        user.name,
+        user.fingerprint.as_deref().unwrap_or("-"),
+        if user.deleted {
+            "deactivated"
+        } else {
+            "active"
+        }
+    )
+}
+
+fn key_line(key: &Key) -> String {
+    format!(
+        "{}\tcreated {}\t{}",
+        key.fingerprint.as_deref().unwrap_or("-"),
+        key.created_at,
+        match (key.revoked_at, &key.revocation_reason) {
+            (Some(revoked_at), reason) => format!(
+                "revoked {} ({})",
+                revoked_at,
+                reason.as_deref().unwrap_or("")
+            ),
+            (None, _) => String::from("current"),
+        }
+    )
+}
+
+/// Runs an admin command against the database opened by `db::init`. Output goes to stdout, errors are returned.
+pub fn run(subcommand: Subcommand) -> Result<(), String> {
+    if subcommand == Subcommand::Help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    db::init().map_err(|e| e.to_string())?;
+    match subcommand {
+        Subcommand::Serve | Subcommand::Help => {}
+        Subcommand::Migrate => println!(
+            "Database is up to date at directory serial {}",
+            db::get_directory_serial().map_err(|e| e.to_string())?
+        ),
+        Subcommand::UsersList => {
+            audit("admin-list-users", "", serde_json::json!({}))?;
+            for user in db::get_users().map_err(|e| e.to_string())? {
+                println!("{}", user_line(&user));
+            }
+        }
+        Subcommand::UsersShow(user_id) => {
+            let user: User = db::get_user(&user_id)
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| format!("No user {}", user_id))?;
+            audit("admin-show-user", &user_id, serde_json::json!({}))?;
+            println!("{}\nreal name: {}", user_line(&user), user.real_name);
+            for key in db::select_key_history(&user_id).map_err(|e| e.to_string())? {
+                println!("{}", key_line(&key));
+            }
+        }
+        Subcommand::KeysRevoke(user_id, reason) => {
+            // audited as key-revoked
+            let revoked: Key = db::revoke_pubkey(&actor(), &user_id, &reason)
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| format!("{} has no key to revoke", user_id))?;
+            println!("Revoked {}", key_line(&revoked));
+        }
+        Subcommand::TokensRevoke(user_id) => {
+            // audited as api-tokens-revoked
+            let revoked: usize = tokens::revoke(&actor(), &user_id).map_err(|e| e.to_string())?;
+            println!("Revoked {} API tokens of {}", revoked, user_id);
+        }
+        Subcommand::Export(file) => {
+            let export: serde_json::Value = db::export().map_err(|e| e.to_string())?;
+            audit(
+                "admin-export",
+                "",
+                serde_json::json!({ "serial": export["serial"] }),
+            )?;
+            let json: String = serde_json::to_string_pretty(&export).unwrap();
+            match file {
+                Some(file) => fs::write(&file, json).map_err(|e| e.to_string())?,
+                None => println!("{}", json),
+            }
+        }
+        Subcommand::Import(file) => {
+            let json: String = fs::read_to_string(&file).map_err(|e| e.to_string())?;
+            let export: serde_json::Value =
+                serde_json::from_str(&json).map_err(|e| e.to_string())?;
+            let users: usize = db::import(&export).map_err(|e| e.to_string())?;
+            audit(
+                "admin-import",
+                "",
+                serde_json::json!({ "file": file, "users": users }),
+            )?;
+            println!("Imported {} users from {}", users, file);
+        }
+        Subcommand::Backup(path) => {
+            db::backup(&path).map_err(|e| e.to_string())?;
+            audit("admin-backup", "", serde_json::json!({ "path": path }))?;
+            println!("Backed up the database to {}", path);
+        }
+        Subcommand::AuditExport(file) => {
+            // the export is recorded first so the file covers it
+            audit(
+                "admin-audit-export",
+                "",
+                serde_json::json!({ "file": file }),
+            )?;
+            let jsonl: String =
+                db::audit_jsonl(&db::AuditQuery::default()).map_err(|e| e.to_string())?;
+            match file {
+                Some(file) => fs::write(&file, jsonl).map_err(|e| e.to_string())?,
+                None => print!("{}", jsonl),
+            }
+        }
+        Subcommand::AuditVerify => {
+            let entries: usize = db::verify_audit_log().map_err(|e| e.to_string())?;
+            println!("The audit log is intact, {} entries", entries);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(&args("")), Ok(Subcommand::Serve));
+        assert_eq!(parse(&args("serve")), Ok(Subcommand::Serve));
+        assert_eq!(
+            parse(&args("users show U1234ABC")),
+            Ok(Subcommand::UsersShow("U1234ABC".to_string()))
+        );
+        assert_eq!(
+            parse(&args("keys revoke U1234ABC")),
+            Ok(Subcommand::KeysRevoke(
+                "U1234ABC".to_string(),
+                "revoked by admin".to_string()
+            ))
+        );
+        assert_eq!(
+            parse(&args("keys revoke U1234ABC laptop stolen")),
+            Ok(Subcommand::KeysRevoke(
+                "U1234ABC".to_string(),
+                "laptop stolen".to_string()
+            ))
+        );
+        assert_eq!(
+            parse(&args("tokens revoke U1234ABC")),
+            Ok(Subcommand::TokensRevoke("U1234ABC".to_string()))
+        );
+        assert_eq!(parse(&args("export")), Ok(Subcommand::Export(None)));
+        assert_eq!(
+            parse(&args("backup /tmp/slackrypt.db3")),
+            Ok(Subcommand::Backup("/tmp/slackrypt.db3".to_string()))
+        );
+        assert_eq!(
+            parse(&args("audit export /tmp/audit.jsonl")),
+            Ok(Subcommand::AuditExport(Some(
+                "/tmp/audit.jsonl".to_string()
+            )))
+        );
+        assert_eq!(parse(&args("audit verify")), Ok(Subcommand::AuditVerify));
+        assert!(parse(&args("users show")).is_err());
+        assert!(parse(&args("import"))
+            .err()
+            .unwrap()
+            .starts_with("Unknown command 'import'\nUsage:"));
+    }
+}
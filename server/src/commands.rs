@@ -0,0 +1,530 @@
+use log::debug;
+use std::vec::Vec;
+
+use crate::bot::Bot;
+use crate::db;
+use crate::db::{ApiToken, Key, LogEntry, User};
+use crate::store::StoreResult;
+use crate::tokens;
+use crate::util;
+
+/// One command of the bot, run as `/slackrypt <name> <args>`, by mentioning the bot or in a DM with it.
+pub struct Command {
+    pub name: &'static str,
+    /// Placeholders of the arguments, optional ones in brackets, e.g. `["@user"]` or `["[command]"]`.
+    pub args: &'static [&'static str],
+    pub help: &'static str,
+    /// Runs the command for the sender with arguments already checked against `args`.
+    run: fn(&Bot, &str, &[&str]) -> StoreResult<String>,
+}
+
+/// The reply when the key directory cannot be read or written, the error itself is only logged.
+pub const UNAVAILABLE: &str = "Sorry, the key directory is unavailable, please try again later.";
+
+impl Command {
+    pub fn usage(&self) -> String {
+        let mut usage: String = self.name.to_string();
+        for arg in self.args {
+            usage.push(' ');
+            usage.push_str(arg);
+        }
+        format!("`{}`", usage)
+    }
+
+    fn required_args(&self) -> usize {
+        self.args.iter().filter(|a| !a.starts_with('[')).count()
+    }
+
+    /// Rejects a wrong number of arguments and `@user` arguments that are no user.
+    fn validate(&self, args: &[&str]) -> Result<(), String> {
+        if args.len() < self.required_args() || args.len() > self.args.len() {
+            return Err(format!("Usage: {}", self.usage()));
+        }
+        for (arg, placeholder) in args.iter().zip(self.args) {
+            if *placeholder == "@user" && !(arg.starts_with("<@") || arg.starts_with('@')) {
+                return Err(format!(
+                    "'{}' is not a user, mention them like @jeff. Usage: {}",
+                    arg,
+                    self.usage()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        args: &["[command]"],
+        help: "lists the commands, or explains one",
+        run: help,
+    },
+    Command {
+        name: "register",
+        args: &[],
+        help: "how to create and register your key pair",
+        run: register,
+    },
+    Command {
+        name: "key",
+        args: &["@user"],
+        help: "the public key of a user",
+        run: key,
+    },
+    Command {
+        name: "whois",
+        args: &["@user"],
+        help: "the fingerprint of a user's key and when it was registered",
+        run: whois,
+    },
+    Command {
+        name: "verify",
+        args: &["@user|code"],
+        help: "checks a user's key against the transparency log and how to compare its fingerprint with them, \
+               or answers the challenge of your own key with its code or decrypted output",
+        run: verify,
+    },
+    Command {
+        name: "mykey",
+        args: &[],
+        help: "your public key, its fingerprint and when you registered it",
+        run: mykey,
+    },
+    Command {
+        name: "fingerprint",
+        args: &[],
+        help: "the fingerprint of your public key",
+        run: fingerprint,
+    },
+    Command {
+        name: "revoke",
+        args: &["[confirm]"],
+        help: "revokes your public key, e.g. when its private key was lost or stolen",
+        run: revoke,
+    },
+    Command {
+        name: "token",
+        args: &["[revoke]"],
+        help: "DMs you an API token for the desktop client to download the key directory, `token revoke` revokes yours",
+        run: token,
+    },
+    Command {
+        name: "status",
+        args: &[],
+        help: "whether your key is registered and how many members have one",
+        run: status,
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// Splits `text` (e.g. `whois @jeff`) into a known command and its validated arguments, no text asks for help.
+pub fn parse(text: &str) -> Result<(&'static Command, Vec<&str>), String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    debug!("args are {:?}", words);
+    let (name, args): (&str, &[&str]) = match words.split_first() {
+        Some((name, args)) => (name, args),
+        None => ("help", &[]),
+    };
+    let command: &Command = find(name).ok_or_else(|| {
+        format!(
+            "I haven't learned how to execute '{}' yet. Try `help`.",
+            name
+        )
+    })?;
+    command.validate(args)?;
+    Ok((command, args.to_vec()))
+}
+
+/// Runs the command in `text` for `sender` and audits it, the reply explains what went wrong if it cannot.
+/// A command that cannot be audited is not run.
+pub fn run(bot: &Bot, sender: &str, text: &str) -> String {
+    let (command, args): (&Command, Vec<&str>) = match parse(text) {
+        Ok(parsed) => parsed,
+        Err(e) => return e,
+    };
+    let reply: StoreResult<String> = db::record_audit_event(
+        sender,
+        "bot-command",
+        sender,
+        &serde_json::json!({ "command": command.name, "args": args }),
+    )
+    .and_then(|_| (command.run)(bot, sender, &args));
+    reply.unwrap_or_else(|e| {
+        log::error!("Could not run {} for {}: {}", command.name, sender, e);
+        String::from(UNAVAILABLE)
+    })
+}
+
+/// A date Slack shows in the reader's time zone, the timestamp where it cannot.
+fn slack_date(timestamp: i64) -> String {
+    format!(
+        "<!date^{}^{{date_short}} {{time}}|{}>",
+        timestamp, timestamp
+    )
+}
+
+/// The user id of a mention, Slack sends `<@U1234ABC>` or, in slash commands, `<@U1234ABC|jeff>`.
+fn mentioned_user_id(arg: &str) -> Option<&str> {
+    let mention: &str = arg.strip_prefix("<@")?.strip_suffix('>')?;
+    mention.split('|').next()
+}
+
+/// The user an `@user` argument refers to, a mention or a handle, the reply to send when there is none.
+fn find_user(bot: &Bot, arg: &str) -> StoreResult<Result<User, String>> {
+    let user: Option<User> = match mentioned_user_id(arg) {
+        Some(user_id) => bot.find_user(user_id)?,
+        None => db::get_user_by_name(arg.trim_start_matches('@'))?,
+    };
+    Ok(user.ok_or_else(|| format!("I don't know the user {}.", arg)))
+}
+
+fn help(_bot: &Bot, _sender: &str, args: &[&str]) -> StoreResult<String> {
+    Ok(match args.first() {
+        Some(name) => match find(name) {
+            Some(command) => format!("{} {}", command.usage(), command.help),
+            None => format!("There is no command '{}'. Try `help`.", name),
+        },
+        None => {
+            let mut response: String =
+                String::from("Commands, as `/slackrypt <command>` or by mentioning me:");
+            for command in COMMANDS {
+                response.push_str(&format!("\n{} {}", command.usage(), command.help));
+            }
+            response.push_str("\nTo get started DM me with `init`.");
+            response
+        }
+    })
+}
+
+fn register(bot: &Bot, _sender: &str, _args: &[&str]) -> StoreResult<String> {
+    Ok(bot.init_instructions())
+}
+
+fn key(bot: &Bot, _sender: &str, args: &[&str]) -> StoreResult<String> {
+    Ok(match find_user(bot, args[0])? {
+        Ok(user) if !user.pubkey.is_empty() => format!(
+            "Public key of {} (fingerprint `{}`):\n```{}```",
+            user.name,
+            user.fingerprint.unwrap_or_default(),
+            user.pubkey
+        ),
+        Ok(user) => format!("{} has not registered a public key yet.", user.name),
+        Err(e) => e,
+    })
+}
+
+fn whois(bot: &Bot, _sender: &str, args: &[&str]) -> StoreResult<String> {
+    let user: User = match find_user(bot, args[0])? {
+        Ok(user) => user,
+        Err(e) => return Ok(e),
+    };
+    let mut response: String = if user.real_name.is_empty() {
+        user.name.clone()
+    } else {
+        format!("{} ({})", user.name, user.real_name)
+    };
+    match (&user.fingerprint, user.updated_at) {
+        (Some(fingerprint), Some(updated_at)) => response.push_str(&format!(
+            " registered the key `{}` on {}.",
+            fingerprint,
+            slack_date(updated_at)
+        )),
+        _ => response.push_str(" has not registered a public key yet."),
+    }
+    if user.deleted {
+        response.push_str(" Their Slack account is deactivated.");
+    }
+    Ok(response)
+}
+
+fn verify(bot: &Bot, sender: &str, args: &[&str]) -> StoreResult<String> {
+    // anything but a mention or handle answers the sender's own key challenge, as `verify <output>` does in a DM
+    if !args[0].starts_with(['<', '@']) {
+        return bot.verify(sender, args[0]);
+    }
+    let user: User = match find_user(bot, args[0])? {
+        Ok(user) => user,
+        Err(e) => return Ok(e),
+    };
+    if user.pubkey.is_empty() {
+        return Ok(format!(
+            "{} has not registered a public key yet.",
+            user.name
+        ));
+    }
+    let logged: Option<LogEntry> = db::get_log_entries(&user.user_id)?.pop();
+    let log_check: &str = match logged {
+        Some(entry) if entry.pubkey == user.pubkey => {
+            "It is the latest key logged for them in the transparency log."
+        }
+        _ => "WARNING: it is not the latest key logged for them in the transparency log, do not trust it!",
+    };
+    Ok(format!(
+        "The key of {} has the fingerprint `{}`. {}\nAsk {} in person or on a call to read you the fingerprint of \
+         their `~/.slackrypt/key.pem.pub` and only trust the key if both match.",
+        user.name,
+        user.fingerprint.unwrap_or_default(),
+        log_check,
+        user.name
+    ))
+}
+
+fn mykey(_bot: &Bot, sender: &str, _args: &[&str]) -> StoreResult<String> {
+    Ok(match db::select_current_key(sender)? {
+        Some(key) => format!(
+            "Your public key (fingerprint `{}`), registered on {}:\n```{}```",
+            key.fingerprint.unwrap_or_default(),
+            slack_date(key.created_at),
+            key.pubkey
+        ),
+        None => String::from("You have not registered a public key yet, see `register`."),
+    })
+}
+
+fn fingerprint(_bot: &Bot, sender: &str, _args: &[&str]) -> StoreResult<String> {
+    Ok(match db::get_user(sender)?.and_then(|u| u.fingerprint) {
+        Some(fingerprint) => format!("The fingerprint of your public key is `{}`", fingerprint),
+        None => String::from("You have not registered a public key yet, see `register`."),
+    })
+}
+
+fn revoke(_bot: &Bot, sender: &str, args: &[&str]) -> StoreResult<String> {
+    let current: Key = match db::select_current_key(sender)? {
+        Some(key) => key,
+        None => return Ok(String::from("You have no public key to revoke.")),
+    };
+    let fingerprint: String = current.fingerprint.unwrap_or_default();
+    Ok(match args.first() {
+        Some(&"confirm") => {
+            db::revoke_pubkey(sender, sender, "revoked")?;
+            format!(
+                "Your public key `{}` is revoked. Register a new key pair to receive messages again, see `register`.",
+                fingerprint
+            )
+        }
+        Some(other) => format!("Unknown option '{}'. Usage: `revoke [confirm]`", other),
+        None => format!(
+            "This revokes your public key `{}`, nobody can encrypt to you until you register a new one. \
+             Run `revoke confirm` to go ahead.",
+            fingerprint
+        ),
+    })
+}
+
+/// The token is always sent in a DM, never as the reply, which could be posted in a channel.
+fn token(bot: &Bot, sender: &str, args: &[&str]) -> StoreResult<String> {
+    Ok(match args.first() {
+        Some(&"revoke") => match tokens::revoke(sender, sender)? {
+            0 => String::from("You have no API tokens to revoke."),
+            revoked => format!(
+                "Revoked your {} API token(s). Run `token` for a new one.",
+                revoked
+            ),
+        },
+        Some(other) => format!("Unknown option '{}'. Usage: `token [revoke]`", other),
+        None => {
+            let (token, issued): (String, ApiToken) =
+                tokens::issue(sender, util::unix_timestamp())?;
+            match bot
+                .api
+                .post_message(sender, &tokens::token_message(&token, issued.expires_at))
+            {
+                Ok(()) => String::from("I sent you a new API token in a DM."),
+                Err(e) => {
+                    log::error!("Could not DM an API token to {}: {}", sender, e);
+                    String::from("Sorry, I could not DM you an API token, please try again later.")
+                }
+            }
+        }
+    })
+}
+
+/// How many members have registered a key and how many there are. Deleted and deactivated users are not members.
+fn member_counts(users: &[User]) -> (usize, usize) {
+    let members: Vec<&User> = users.iter().filter(|u| !u.deleted).collect();
+    let registered: usize = members.iter().filter(|u| !u.pubkey.is_empty()).count();
+    (registered, members.len())
+}
+
+fn status(_bot: &Bot, sender: &str, _args: &[&str]) -> StoreResult<String> {
+    let users: Vec<User> = db::get_users()?;
+    let (registered, members): (usize, usize) = member_counts(&users);
+    let mine: String = match users.iter().find(|u| u.user_id == sender) {
+        Some(user) if !user.pubkey.is_empty() => format!(
+            "Your public key is registered (fingerprint `{}`).",
+            user.fingerprint.as_deref().unwrap_or_default()
+        ),
+        _ if db::select_pending_key(sender)?.is_some() => String::from(
+            "Your public key is waiting for you to reply with `verify <output>` of its challenge, or `verify <code>` \
+             with the code I DMed you when the client submitted it.",
+        ),
+        _ => String::from("You have not registered a public key yet, see `register`."),
+    };
+    Ok(format!(
+        "{}\n{} of {} members have registered a public key.",
+        mine, registered, members
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Member;
+    use crate::slack_api::SlackApi;
+    use rand::rngs::OsRng;
+    use rsa::pkcs8::{EncodePublicKey, LineEnding};
+    use rsa::RsaPrivateKey;
+
+    fn bot() -> Bot {
+        db::init_memory();
+        Bot::new("example.com", "U0LAN0Z89", SlackApi::new(""))
+    }
+
+    fn pubkey() -> String {
+        let key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        key.to_public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("").unwrap().0.name, "help");
+        assert_eq!(parse(" whois  @jeff ").unwrap().1, vec!["@jeff"]);
+        assert_eq!(
+            parse("whois <@U1234ABC|jeff>").unwrap().1,
+            vec!["<@U1234ABC|jeff>"]
+        );
+        assert_eq!(
+            parse("dance").err().unwrap(),
+            "I haven't learned how to execute 'dance' yet. Try `help`."
+        );
+        assert_eq!(parse("whois").err().unwrap(), "Usage: `whois @user`");
+        assert_eq!(
+            parse("whois @jeff @rade").err().unwrap(),
+            "Usage: `whois @user`"
+        );
+        assert!(parse("key jeff")
+            .err()
+            .unwrap()
+            .starts_with("'jeff' is not a user"));
+        assert_eq!(parse("revoke").unwrap().1.len(), 0);
+        assert_eq!(parse("status now").err().unwrap(), "Usage: `status`");
+    }
+
+    #[test]
+    fn test_help() {
+        let bot: Bot = bot();
+        let list: String = run(&bot, "U1234ABC", "help");
+        for command in COMMANDS {
+            assert!(list.contains(&command.usage()));
+        }
+        assert_eq!(
+            run(&bot, "U1234ABC", "help whois"),
+            "`whois @user` the fingerprint of a user's key and when it was registered"
+        );
+        assert_eq!(
+            run(&bot, "U1234ABC", "help dance"),
+            "There is no command 'dance'. Try `help`."
+        );
+        assert!(run(&bot, "U1234ABC", "register").contains("https://example.com/init.sh"));
+    }
+
+    #[test]
+    fn test_commands() {
+        let bot: Bot = bot();
+        db::upsert_members(&[
+            Member::new("UCMD0001", "alice", "Alice Liddell"),
+            Member::new("UCMD0002", "bob", ""),
+        ])
+        .unwrap();
+        let pem: String = pubkey();
+        db::upsert_pubkey("UCMD0001", "UCMD0001", "alice", &pem).unwrap();
+        let fingerprint: String = db::get_user("UCMD0001")
+            .unwrap()
+            .unwrap()
+            .fingerprint
+            .unwrap();
+
+        let whois: String = run(&bot, "UCMD0002", "whois <@UCMD0001|alice>");
+        assert!(whois.starts_with(&format!(
+            "alice (Alice Liddell) registered the key `{}` on <!date^",
+            fingerprint
+        )));
+        assert_eq!(
+            run(&bot, "UCMD0001", "whois @bob"),
+            "bob has not registered a public key yet."
+        );
+        assert_eq!(
+            run(&bot, "UCMD0001", "whois @nobody"),
+            "I don't know the user @nobody."
+        );
+        assert!(run(&bot, "UCMD0002", "verify @alice")
+            .contains("It is the latest key logged for them in the transparency log."));
+        assert!(run(&bot, "UCMD0002", "verify 12345678")
+            .starts_with("I have no public key waiting for verification from you."));
+        assert!(run(&bot, "UCMD0002", "key @alice").contains(pem.trim()));
+
+        assert!(run(&bot, "UCMD0001", "mykey").contains(&fingerprint));
+        assert_eq!(
+            run(&bot, "UCMD0002", "mykey"),
+            "You have not registered a public key yet, see `register`."
+        );
+        assert!(run(&bot, "UCMD0002", "status").contains("of"));
+
+        assert!(run(&bot, "UCMD0001", "revoke").contains("Run `revoke confirm` to go ahead."));
+        assert!(db::select_current_key("UCMD0001").unwrap().is_some());
+        assert!(run(&bot, "UCMD0001", "revoke confirm").starts_with("Your public key"));
+        assert_eq!(db::select_current_key("UCMD0001").unwrap(), None);
+        assert_eq!(
+            run(&bot, "UCMD0001", "revoke confirm"),
+            "You have no public key to revoke."
+        );
+        assert_eq!(
+            run(&bot, "UCMD0002", "fingerprint"),
+            "You have not registered a public key yet, see `register`."
+        );
+        assert_eq!(
+            run(&bot, "UCMD0002", "token revoke"),
+            "You have no API tokens to revoke."
+        );
+        assert_eq!(
+            run(&bot, "UCMD0002", "token now"),
+            "Unknown option 'now'. Usage: `token [revoke]`"
+        );
+    }
+
+    #[test]
+    fn test_member_counts() {
+        let user = |user_id: &str, deleted: bool, pubkey: &str| User {
+            user_id: user_id.to_string(),
+            name: user_id.to_lowercase(),
+            real_name: String::new(),
+            deleted,
+            pubkey: pubkey.to_string(),
+            fingerprint: None,
+            updated_at: None,
+            revision: 1,
+        };
+        let users: Vec<User> = vec![
+            user("U1234ABC", false, "KEY1"),
+            user("U5678DEF", false, ""),
+            // a tombstone and a deactivated user who still has a key
+            user("U9999XYZ", true, ""),
+            user("U0000AAA", true, "KEY2"),
+        ];
+        assert_eq!(member_counts(&users), (1, 2));
+    }
+
+    #[test]
+    fn test_mentioned_user_id() {
+        assert_eq!(mentioned_user_id("<@U1234ABC>"), Some("U1234ABC"));
+        assert_eq!(mentioned_user_id("<@U1234ABC|jeff>"), Some("U1234ABC"));
+        assert_eq!(mentioned_user_id("@jeff"), None);
+        assert_eq!(mentioned_user_id("jeff"), None);
+    }
+}
@@ -0,0 +1,140 @@
+use std::path::Path;
+use std::sync::OnceLock;
+
+use rand::rngs::OsRng;
+use rsa::pkcs8::{
+    DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding,
+};
+use rsa::traits::PublicKeyParts;
+use rsa::{Pkcs1v15Encrypt, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
+
+use crate::util;
+
+const SIGNING_KEY_FILE_NAME: &str = "/server.pem";
+const SIGNING_KEY_BITS: usize = 3072;
+
+/// Smallest user key accepted for registration, matching the client's default `SCRYPT_KEY_SIZE`.
+pub const MIN_KEY_BITS: usize = 2048;
+
+static SIGNING_KEY: OnceLock<RsaPrivateKey> = OnceLock::new();
+
+/// The server's own RSA key used to sign directory snapshots.
+/// It is created under `default_dir()` on first use and clients pin its public half at install time.
+pub fn signing_key() -> &'static RsaPrivateKey {
+    SIGNING_KEY.get_or_init(|| {
+        let path: String = util::default_dir() + SIGNING_KEY_FILE_NAME;
+        if Path::new(&path).exists() {
+            RsaPrivateKey::read_pkcs8_pem_file(&path).expect("Could not read server signing key!")
+        } else {
+            log::info!(
+                "Creating {} bit server signing key {}...",
+                SIGNING_KEY_BITS,
+                &path
+            );
+            let key = RsaPrivateKey::new(&mut OsRng, SIGNING_KEY_BITS)
+                .expect("Could not generate server signing key!");
+            key.write_pkcs8_pem_file(&path, LineEnding::LF)
+                .expect("Could not write server signing key!");
+            key
+        }
+    })
+}
+
+pub fn signing_public_key_pem() -> String {
+    let public_key: RsaPublicKey = signing_key().to_public_key();
+    public_key
+        .to_public_key_pem(LineEnding::LF)
+        .expect("Could not encode server public key!")
+}
+
+/// PKCS#1 v1.5 signature over the SHA-256 digest of `data`, base64 encoded.
+pub fn sign(data: &[u8]) -> String {
+    sign_with(signing_key(), data)
+}
+
+fn sign_with(key: &RsaPrivateKey, data: &[u8]) -> String {
+    let digest = Sha256::digest(data);
+    let signature: Vec<u8> = key
+        .sign(Pkcs1v15Sign::new::<Sha256>(), &digest)
+        .expect("Could not sign data!");
+    util::to_base64_str(&signature)
+}
+
+/// Parses a PEM encoded (SPKI) RSA public key and rejects keys smaller than `min_bits`.
+pub fn validate_public_key(pubkey: &str, min_bits: usize) -> Result<RsaPublicKey, String> {
+    let public_key = RsaPublicKey::from_public_key_pem(pubkey).map_err(|e| e.to_string())?;
+    let bits: usize = public_key.size() * 8;
+    if bits < min_bits {
+        return Err(format!(
+            "the key is {} bits but at least {} bits are required",
+            bits, min_bits
+        ));
+    }
+    Ok(public_key)
+}
+
+/// PKCS#1 v1.5 encryption to a user's key, base64 encoded. This is the padding the client decrypts with.
+pub fn encrypt_to(public_key: &RsaPublicKey, data: &[u8]) -> String {
+    let ciphertext: Vec<u8> = public_key
+        .encrypt(&mut OsRng, Pkcs1v15Encrypt, data)
+        .expect("Could not encrypt data!");
+    util::to_base64_str(&ciphertext)
+}
+
+/// SHA-256 over the DER bytes of a PEM encoded public key as lowercase hex pairs separated by ':', same as the client.
+pub fn fingerprint(pubkey: &str) -> Option<String> {
+    let public_key = RsaPublicKey::from_public_key_pem(pubkey.trim()).ok()?;
+    let der = public_key.to_public_key_der().ok()?;
+    let hex: Vec<String> = Sha256::digest(der.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Some(hex.join(":"))
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+    #[test]
+    fn test_sign_with() {
+        let key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let signature: Vec<u8> = STANDARD.decode(sign_with(&key, b"serial=1")).unwrap();
+        let digest = Sha256::digest(b"serial=1");
+        let public_key: RsaPublicKey = key.to_public_key();
+        assert!(public_key
+            .verify(Pkcs1v15Sign::new::<Sha256>(), &digest, &signature)
+            .is_ok());
+        let digest = Sha256::digest(b"serial=2");
+        assert!(public_key
+            .verify(Pkcs1v15Sign::new::<Sha256>(), &digest, &signature)
+            .is_err());
+    }
+
+    #[test]
+    fn test_validate_public_key() {
+        let key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        let pem: String = key
+            .to_public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        assert!(validate_public_key(&pem, 1024).is_ok());
+        assert!(validate_public_key(&pem, MIN_KEY_BITS).is_err());
+        assert!(validate_public_key(
+            "-----BEGIN PUBLIC KEY-----\nfoo\n-----END PUBLIC KEY-----",
+            1024
+        )
+        .is_err());
+        assert_eq!(fingerprint(&pem).unwrap().len(), 32 * 3 - 1);
+        assert_eq!(fingerprint("foo"), None);
+    }
+}
@@ -1,115 +1,346 @@
-use rusqlite::{params, Connection, Result};
+use std::sync::OnceLock;
 use std::vec::Vec;
 
+use crate::config;
+use crate::events::{self, EventKind, KeyEvent};
+use crate::merkle;
+use crate::store::{self, KeyDirectory, StoreError, StoreResult};
 use crate::util;
 
-#[derive(Debug)]
-struct User {
-    id: i32,
-    user_id: String,
-    name: String,
-    pubkey: String,
+pub use crate::store::{ApiToken, AuditEvent, AuditQuery, Key, LogEntry, Member, PendingKey, User};
+
+/// Audit log action of `delete_user`, key changes are audited as their `EventKind`.
+pub const USER_DELETED: &str = "user-deleted";
+
+static DIRECTORY: OnceLock<Box<dyn KeyDirectory>> = OnceLock::new();
+
+/// The backend chosen by `init`. Shared between the bot thread and the HTTP workers.
+fn directory() -> &'static dyn KeyDirectory {
+    DIRECTORY
+        .get()
+        .expect("The key directory is used before db::init()!")
+        .as_ref()
 }
 
-fn get_connection() -> Result<Connection> {
-    let path: String = util::default_dir() + "/slackrypt.db3";
-    let conn = Connection::open(&path)?;
-    log::debug!("is autocommit? {}", conn.is_autocommit());
-    Ok(conn)
+/// Syncs the Slack workspace members in a single transaction.
+/// New members are added without a key and renamed members get their new name.
+pub fn upsert_members(members: &[Member]) -> StoreResult<()> {
+    directory().sync_users(members)
 }
 
-pub fn insert_pubkeys(user_info: &[(&str, &str, &str)]) -> Result<()> {
-    for (user_id, name, pubkey) in user_info {
-        if !has_pubkey(user_id) {
-            insert_pubkey(user_id, name, pubkey).unwrap();
-        }
-    }
+/// Audits a key change made by `actor` and publishes it to the `/events` subscribers.
+fn key_changed(actor: &str, event: KeyEvent, detail: serde_json::Value) -> StoreResult<()> {
+    let mut detail: serde_json::Value = detail;
+    detail["fingerprint"] = serde_json::json!(event.fingerprint);
+    detail["revision"] = serde_json::json!(event.revision);
+    record_audit_event(actor, event.kind.as_str(), &event.user_id, &detail)?;
+    events::publish(event);
     Ok(())
 }
 
-fn insert_pubkey(user_id: &str, name: &str, pubkey: &str) -> Result<()> {
-    let conn: Connection = get_connection().unwrap();
-    conn.execute(
-        "INSERT INTO users (user_id, name, pubkey) VALUES (?1, ?2, ?3)",
-        params![user_id, name, pubkey],
-    )?;
+/// Stores the user's key, its history entry and its log leaf in a single transaction, then audits and
+/// publishes a `key-added` or `key-rotated` event. `actor` is who registered it, usually the user themselves.
+pub fn upsert_pubkey(actor: &str, user_id: &str, name: &str, pubkey: &str) -> StoreResult<()> {
+    let previous: Option<Key> = directory().current_key(user_id)?;
+    directory().upsert_pubkey(user_id, name, pubkey)?;
+    if let Some(user) = directory().user(user_id)? {
+        let detail: serde_json::Value = serde_json::json!({
+            "previous_fingerprint": previous.as_ref().and_then(|k| k.fingerprint.clone()),
+        });
+        let event = KeyEvent {
+            kind: match previous {
+                Some(_) => EventKind::Rotated,
+                None => EventKind::Added,
+            },
+            user_id: user.user_id,
+            name: user.name,
+            fingerprint: user.fingerprint,
+            revision: user.revision,
+        };
+        key_changed(actor, event, detail)?;
+    }
     Ok(())
 }
 
-pub fn upsert_pubkey(user_id: &str, name: &str, pubkey: &str) -> Result<()> {
-    if has_pubkey(user_id) {
-        update_pubkey(user_id, pubkey)
-    } else {
-        insert_pubkey(user_id, name, pubkey)
+/// Revokes the user's current key, then audits and publishes a `key-revoked` event. `None` when they had no key.
+pub fn revoke_pubkey(actor: &str, user_id: &str, reason: &str) -> StoreResult<Option<Key>> {
+    let revoked: Option<Key> = directory().revoke_key(user_id, reason)?;
+    if let (Some(key), Some(user)) = (&revoked, directory().user(user_id)?) {
+        let event = KeyEvent {
+            kind: EventKind::Revoked,
+            user_id: user.user_id,
+            name: user.name,
+            fingerprint: key.fingerprint.clone(),
+            revision: user.revision,
+        };
+        key_changed(actor, event, serde_json::json!({ "reason": reason }))?;
     }
+    Ok(revoked)
 }
 
-fn update_pubkey(user_id: &str, pubkey: &str) -> Result<()> {
-    let conn: Connection = get_connection().unwrap();
-    conn.execute(
-        "UPDATE users SET pubkey=?1 WHERE user_id=?2",
-        params![pubkey, user_id],
+/// Removes a departed user from the directory and audits it. Their current key is revoked first,
+/// publishing a `key-revoked` event, see `KeyDirectory::delete_user`. Returns whether there was anything to delete.
+pub fn delete_user(actor: &str, user_id: &str) -> StoreResult<bool> {
+    let current: Option<Key> = directory().current_key(user_id)?;
+    let user: Option<User> = directory().user(user_id)?;
+    let deleted: bool = directory().delete_user(user_id)?;
+    if !deleted {
+        return Ok(false);
+    }
+    let revision: i64 = directory().directory_serial()?;
+    record_audit_event(
+        actor,
+        USER_DELETED,
+        user_id,
+        &serde_json::json!({ "revision": revision }),
     )?;
-    Ok(())
+    if let (Some(key), Some(user)) = (current, user) {
+        let event = KeyEvent {
+            kind: EventKind::Revoked,
+            user_id: user.user_id,
+            name: user.name,
+            fingerprint: key.fingerprint,
+            revision,
+        };
+        key_changed(
+            actor,
+            event,
+            serde_json::json!({ "reason": "user deleted" }),
+        )?;
+    }
+    Ok(true)
 }
 
-fn has_pubkey(user_id: &str) -> bool {
-    let pubkeys: Vec<String> = select_pubkey(user_id).unwrap();
-    !pubkeys.is_empty()
+pub fn select_current_key(user_id: &str) -> StoreResult<Option<Key>> {
+    directory().current_key(user_id)
 }
 
-pub fn select_pubkey(user_id: &str) -> Result<Vec<String>> {
-    let conn: Connection = get_connection().unwrap();
-    let mut stmt = conn.prepare("SELECT pubkey FROM users WHERE user_id = ?")?;
-    let mut rows = stmt.query([&user_id])?;
+pub fn select_key_history(user_id: &str) -> StoreResult<Vec<Key>> {
+    directory().key_history(user_id)
+}
 
-    let mut pubkeys = Vec::new();
-    while let Some(row) = rows.next()? {
-        let pubkey: String = row.get(0)?;
-        pubkeys.push(pubkey);
-    }
+pub fn select_key_by_fingerprint(fingerprint: &str) -> StoreResult<Option<Key>> {
+    directory().key_by_fingerprint(fingerprint)
+}
+
+pub fn get_log_leaf_hashes() -> StoreResult<Vec<merkle::Hash>> {
+    directory().log_leaf_hashes()
+}
+
+pub fn get_log_entries(user_id: &str) -> StoreResult<Vec<LogEntry>> {
+    directory().log_entries(user_id)
+}
+
+pub fn get_directory_serial() -> StoreResult<i64> {
+    directory().directory_serial()
+}
+
+pub fn upsert_pending_key(
+    user_id: &str,
+    name: &str,
+    pubkey: &str,
+    nonce_hash: &str,
+) -> StoreResult<()> {
+    directory().upsert_pending_key(user_id, name, pubkey, nonce_hash)
+}
+
+pub fn select_pending_key(user_id: &str) -> StoreResult<Option<PendingKey>> {
+    directory().pending_key(user_id)
+}
+
+pub fn delete_pending_key(user_id: &str) -> StoreResult<()> {
+    directory().delete_pending_key(user_id)
+}
+
+pub fn get_users() -> StoreResult<Vec<User>> {
+    directory().users()
+}
+
+pub fn get_user(user_id: &str) -> StoreResult<Option<User>> {
+    directory().user(user_id)
+}
+
+/// Users changed after directory serial `since`.
+pub fn get_changes(since: i64) -> StoreResult<Vec<User>> {
+    directory().changes(since)
+}
+
+/// Appends `actor` did `action` to the user `subject` to the audit log.
+pub fn record_audit_event(
+    actor: &str,
+    action: &str,
+    subject: &str,
+    detail: &serde_json::Value,
+) -> StoreResult<AuditEvent> {
+    directory().append_audit_event(actor, action, subject, &detail.to_string())
+}
+
+/// Audit log entries matching `query`, oldest first.
+pub fn get_audit_events(query: &AuditQuery) -> StoreResult<Vec<AuditEvent>> {
+    directory().audit_events(query)
+}
+
+/// Checks the hash chain of the whole audit log and returns how many entries it has,
+/// or an error naming the first entry that was modified or does not follow its predecessor.
+pub fn verify_audit_log() -> StoreResult<usize> {
+    let events: Vec<AuditEvent> = directory().audit_events(&AuditQuery::default())?;
+    store::verify_audit_chain(&events).map_err(|e| StoreError::new(&e))
+}
+
+/// An audit log entry as served by the admin API and exported for SIEMs, with `detail` as a JSON object.
+pub fn audit_event_json(event: &AuditEvent) -> serde_json::Value {
+    serde_json::json!({
+        "id": event.id,
+        "created_at": event.created_at,
+        "actor": event.actor,
+        "action": event.action,
+        "subject": event.subject,
+        "detail": serde_json::from_str::<serde_json::Value>(&event.detail)
+            .unwrap_or_else(|_| serde_json::json!(event.detail)),
+        "prev_hash": event.prev_hash,
+        "hash": event.hash,
+    })
+}
+
+/// The audit log entries matching `query` as JSON lines, one `audit_event_json` object per line.
+pub fn audit_jsonl(query: &AuditQuery) -> StoreResult<String> {
+    Ok(get_audit_events(query)?
+        .iter()
+        .map(|event| audit_event_json(event).to_string() + "\n")
+        .collect())
+}
+
+pub fn insert_api_token(token: &ApiToken) -> StoreResult<()> {
+    directory().insert_api_token(token)
+}
+
+pub fn select_api_token(token_hash: &str) -> StoreResult<Option<ApiToken>> {
+    directory().api_token(token_hash)
+}
+
+/// Revokes every API token of the user, returns how many were not revoked yet.
+pub fn revoke_api_tokens(user_id: &str) -> StoreResult<usize> {
+    directory().revoke_api_tokens(user_id)
+}
+
+/// Slack handles are unique within a workspace.
+pub fn get_user_by_name(name: &str) -> StoreResult<Option<User>> {
+    directory().user_by_name(name)
+}
+
+/// The `id,name,pubkey` line of a user served by `/pubkey/users`, `/pubkey/directory` and `/changes`.
+pub fn to_csv(user: User) -> String {
+    user.user_id + "," + &user.name + "," + &user.pubkey
+}
+
+pub fn get_users_all() -> StoreResult<Vec<String>> {
+    let users: Vec<User> = directory().users()?;
+    Ok(users.into_iter().map(to_csv).collect())
+}
+
+/// Uses an in-memory directory for unit tests of the bot, unless one is open already.
+#[cfg(test)]
+pub fn init_memory() {
+    let _ = DIRECTORY.set(Box::new(store::memory::MemoryDirectory::default()));
+}
 
-    Ok(pubkeys)
+fn key_export(key: &Key) -> serde_json::Value {
+    serde_json::json!({
+        "pubkey": key.pubkey,
+        "fingerprint": key.fingerprint,
+        "created_at": key.created_at,
+        "revoked_at": key.revoked_at,
+        "revocation_reason": key.revocation_reason,
+    })
+}
+
+/// Every user with their whole key history, oldest key first, for backups and moving to another server.
+pub fn export() -> StoreResult<serde_json::Value> {
+    export_from(directory())
+}
+
+fn export_from(directory: &dyn KeyDirectory) -> StoreResult<serde_json::Value> {
+    let serial: i64 = directory.directory_serial()?;
+    let mut users: Vec<serde_json::Value> = Vec::new();
+    for user in directory.users()? {
+        let keys: Vec<serde_json::Value> = directory
+            .key_history(&user.user_id)?
+            .iter()
+            .map(key_export)
+            .collect();
+        users.push(serde_json::json!({
+            "id": user.user_id,
+            "name": user.name,
+            "real_name": user.real_name,
+            "deleted": user.deleted,
+            "keys": keys,
+        }));
+    }
+    Ok(serde_json::json!({
+        "serial": serial,
+        "exported_at": util::unix_timestamp(),
+        "users": users,
+    }))
 }
 
-pub fn get_users_all() -> Result<Vec<String>> {
-    let conn: Connection = get_connection().unwrap();
-    let mut stmt = conn.prepare("SELECT user_id, name, pubkey FROM users ORDER BY name")?;
-    let mut rows = stmt.query([])?;
+/// Loads an `export` into an empty directory and returns how many users it had. Each user's keys are registered
+/// again in order, so the transparency log starts over and only the current key keeps its revocation reason.
+pub fn import(export: &serde_json::Value) -> StoreResult<usize> {
+    import_into(directory(), export)
+}
 
-    let mut users = Vec::new();
-    while let Some(row) = rows.next()? {
-        let user_id: String = row.get(0)?;
-        let name: String = row.get(1)?;
-        let pubkey: String = row.get(2)?;
-        let user_csv = user_id + "," + &name + "," + &pubkey;
-        users.push(user_csv);
+fn import_into(directory: &dyn KeyDirectory, export: &serde_json::Value) -> StoreResult<usize> {
+    if directory.directory_serial()? > 0 {
+        return Err(StoreError::new(
+            "Refusing to import into a directory that is not empty",
+        ));
+    }
+    let users: &Vec<serde_json::Value> = export["users"]
+        .as_array()
+        .ok_or_else(|| StoreError::new("The export has no users"))?;
+    let mut members: Vec<Member> = Vec::new();
+    for user in users {
+        let (user_id, name): (&str, &str) = match (user["id"].as_str(), user["name"].as_str()) {
+            (Some(user_id), Some(name)) => (user_id, name),
+            _ => return Err(StoreError::new(&format!("Malformed user {}", user))),
+        };
+        members.push(Member {
+            deleted: user["deleted"].as_bool().unwrap_or(false),
+            ..Member::new(user_id, name, user["real_name"].as_str().unwrap_or(""))
+        });
     }
+    directory.sync_users(&members)?;
 
-    Ok(users)
-}
-
-pub fn init() -> Result<()> {
-    log::info!("Starting SQLite3...");
-    let conn: Connection = get_connection().unwrap();
-
-    match conn.execute(
-        "CREATE TABLE users (
-                  id              INTEGER PRIMARY KEY,
-                  user_id         TEXT UNIQUE NOT NULL,
-                  name            TEXT NOT NULL,
-                  pubkey          TEXT NOT NULL
-                  )",
-        params![],
-    ) {
-        Ok(_) => true,
-        Err(_) => {
-            log::warn!("Ignore since user table might already exist.");
-            true
+    for (user, member) in users.iter().zip(&members) {
+        let keys: &[serde_json::Value] = user["keys"].as_array().map_or(&[], |k| k.as_slice());
+        for key in keys {
+            let pubkey: &str = key["pubkey"]
+                .as_str()
+                .ok_or_else(|| StoreError::new(&format!("Malformed key of {}", member.user_id)))?;
+            directory.upsert_pubkey(&member.user_id, &member.name, pubkey)?;
         }
-    };
+        if let Some(last) = keys.last().filter(|k| !k["revoked_at"].is_null()) {
+            let reason: &str = last["revocation_reason"].as_str().unwrap_or("revoked");
+            directory.revoke_key(&member.user_id, reason)?;
+        }
+    }
+    Ok(members.len())
+}
+
+/// Copies the whole database to `path`, see `KeyDirectory::backup`.
+pub fn backup(path: &str) -> StoreResult<()> {
+    directory().backup(path)
+}
+
+/// Opens the backend named by the configured `database_url` (`SLACKRYPT_DATABASE_URL` overrides it),
+/// SQLite in the default dir unless set.
+pub fn init() -> StoreResult<()> {
+    let opened: Box<dyn KeyDirectory> = store::open(&config::get().database_url)?;
+    if DIRECTORY.set(opened).is_err() {
+        log::warn!("db::init() called more than once, keeping the first key directory");
+    }
 
-    let users: Vec<String> = get_users_all().unwrap();
+    let users: Vec<String> = get_users_all()?;
 
     log::debug!("Current users:");
     for user in users {
@@ -117,3 +348,84 @@ pub fn init() -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryDirectory;
+
+    #[test]
+    fn test_audit_log() {
+        init_memory();
+        upsert_members(&[Member::new("UADT0001", "audited", "")]).unwrap();
+        upsert_pubkey("UADT0001", "UADT0001", "audited", "KEY1").unwrap();
+        revoke_pubkey("admin:alice", "UADT0001", "compromised").unwrap();
+
+        let events: Vec<AuditEvent> = get_audit_events(&AuditQuery {
+            subject: Some("UADT0001".to_string()),
+            ..AuditQuery::default()
+        })
+        .unwrap();
+        let actions: Vec<(&str, &str)> = events
+            .iter()
+            .map(|e| (e.actor.as_str(), e.action.as_str()))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![("UADT0001", "key-added"), ("admin:alice", "key-revoked")]
+        );
+        let revoked: serde_json::Value = audit_event_json(&events[1]);
+        assert_eq!(revoked["detail"]["reason"], "compromised");
+        assert_eq!(revoked["prev_hash"], events[0].hash);
+
+        let jsonl: String = audit_jsonl(&AuditQuery::default()).unwrap();
+        assert!(jsonl
+            .lines()
+            .all(|line| serde_json::from_str::<serde_json::Value>(line).is_ok()));
+        // other tests may append in the meantime
+        assert!(verify_audit_log().unwrap() >= jsonl.lines().count());
+    }
+
+    #[test]
+    fn test_export_import() {
+        let source = MemoryDirectory::default();
+        source
+            .sync_users(&[
+                Member::new("U1234ABC", "jeff", "Jeff Rade"),
+                Member::new("U5678DEF", "rade", ""),
+                Member {
+                    deleted: true,
+                    ..Member::new("U0000001", "gone", "")
+                },
+            ])
+            .unwrap();
+        source.upsert_pubkey("U1234ABC", "jeff", "KEY1").unwrap();
+        source.upsert_pubkey("U1234ABC", "jeff", "KEY2").unwrap();
+        source.upsert_pubkey("U0000001", "gone", "KEY3").unwrap();
+        source.revoke_key("U0000001", "compromised").unwrap();
+        let export: serde_json::Value = export_from(&source).unwrap();
+        assert_eq!(export["serial"], 5);
+        assert_eq!(export["users"][1]["keys"][1]["pubkey"], "KEY2");
+
+        let target = MemoryDirectory::default();
+        assert_eq!(import_into(&target, &export).unwrap(), 3);
+        // everything but the revisions and the creation times
+        let summary = |directory: &MemoryDirectory| -> Vec<(String, String, bool, String)> {
+            directory
+                .users()
+                .unwrap()
+                .into_iter()
+                .map(|u| (u.user_id, u.real_name, u.deleted, u.pubkey))
+                .collect()
+        };
+        assert_eq!(summary(&target), summary(&source));
+        assert_eq!(target.key_history("U1234ABC").unwrap().len(), 2);
+        let gone: Vec<Key> = target.key_history("U0000001").unwrap();
+        assert_eq!(gone[0].revocation_reason.as_deref(), Some("compromised"));
+        assert_eq!(target.log_leaf_hashes().unwrap().len(), 3);
+
+        // only into an empty directory
+        assert!(import_into(&target, &export).is_err());
+        assert!(import_into(&MemoryDirectory::default(), &serde_json::json!({})).is_err());
+    }
+}
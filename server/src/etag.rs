@@ -0,0 +1,90 @@
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::Outcome;
+
+/// The `If-None-Match` header of a request, if any.
+pub struct IfNoneMatch(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfNoneMatch {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        Outcome::Success(IfNoneMatch(
+            request
+                .headers()
+                .get_one("If-None-Match")
+                .map(|v| v.to_string()),
+        ))
+    }
+}
+
+impl IfNoneMatch {
+    /// Whether the client already has `etag`, also accepting `*`, lists and weak validators.
+    pub fn matches(&self, etag: &str) -> bool {
+        match self.0 {
+            None => false,
+            Some(ref header) => header
+                .split(',')
+                .map(|t| t.trim())
+                .any(|t| t == "*" || t.trim_start_matches("W/") == etag),
+        }
+    }
+}
+
+/// The ETag of everything derived from one directory revision (the directory serial).
+pub fn revision_etag(revision: i64) -> String {
+    format!("\"{}\"", revision)
+}
+
+/// A response carrying an `ETag`, or an empty `304 Not Modified` when the client sent a matching `If-None-Match`.
+pub enum Tagged<R> {
+    Fresh(String, R),
+    NotModified(String),
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for Tagged<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        match self {
+            Tagged::Fresh(etag, responder) => Response::build_from(responder.respond_to(request)?)
+                .raw_header("ETag", etag)
+                .ok(),
+            Tagged::NotModified(etag) => Response::build()
+                .status(Status::NotModified)
+                .raw_header("ETag", etag)
+                .ok(),
+        }
+    }
+}
+
+/// Only builds the body when the client's copy of `revision` is stale. An error building it, e.g. because the key
+/// directory cannot be read, is returned instead.
+pub fn tagged<R, E, F: FnOnce() -> Result<R, E>>(
+    if_none_match: &IfNoneMatch,
+    revision: i64,
+    body: F,
+) -> Result<Tagged<R>, E> {
+    let etag: String = revision_etag(revision);
+    if if_none_match.matches(&etag) {
+        Ok(Tagged::NotModified(etag))
+    } else {
+        Ok(Tagged::Fresh(etag, body()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let etag: String = revision_etag(42);
+        assert_eq!(etag, "\"42\"");
+        assert!(!IfNoneMatch(None).matches(&etag));
+        assert!(IfNoneMatch(Some("\"42\"".to_string())).matches(&etag));
+        assert!(IfNoneMatch(Some("W/\"42\"".to_string())).matches(&etag));
+        assert!(IfNoneMatch(Some("\"41\", \"42\"".to_string())).matches(&etag));
+        assert!(IfNoneMatch(Some("*".to_string())).matches(&etag));
+        assert!(!IfNoneMatch(Some("\"41\"".to_string())).matches(&etag));
+    }
+}
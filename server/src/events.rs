@@ -0,0 +1,339 @@
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ratelimit;
+use crate::tokens;
+use crate::util;
+
+/// How often an idle stream gets a comment line, so proxies keep it open and dead clients are noticed.
+const KEEPALIVE_SECS: u64 = 15;
+
+/// The whole request head must arrive within this time and size, so slow or endless requests cannot hold a thread.
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+const MAX_REQUEST_BYTES: u64 = 8 * 1024;
+
+/// Streams open at most, each has its own thread. Further connections get `503 Service Unavailable`.
+const MAX_STREAMS: usize = 1_000;
+
+static OPEN_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    Added,
+    Rotated,
+    Revoked,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Added => "key-added",
+            EventKind::Rotated => "key-rotated",
+            EventKind::Revoked => "key-revoked",
+        }
+    }
+}
+
+/// A change to a user's key. It carries no PEM: clients fetch the signed `/changes` delta for `revision` instead
+/// of trusting an unsigned push.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyEvent {
+    pub kind: EventKind,
+    pub user_id: String,
+    pub name: String,
+    pub fingerprint: Option<String>,
+    pub revision: i64,
+}
+
+impl KeyEvent {
+    /// The event in `text/event-stream` format, the directory revision is its `id`.
+    pub fn to_sse(&self) -> String {
+        let data: serde_json::Value = serde_json::json!({
+            "user_id": self.user_id,
+            "name": self.name,
+            "fingerprint": self.fingerprint,
+            "revision": self.revision,
+        });
+        format!(
+            "event: {}\nid: {}\ndata: {}\n\n",
+            self.kind.as_str(),
+            self.revision,
+            data
+        )
+    }
+}
+
+static SUBSCRIBERS: Mutex<Vec<Sender<KeyEvent>>> = Mutex::new(Vec::new());
+
+pub fn subscribe() -> Receiver<KeyEvent> {
+    let (tx, rx) = mpsc::channel();
+    SUBSCRIBERS.lock().unwrap().push(tx);
+    rx
+}
+
+/// Sends the event to every open stream and forgets the ones that were closed.
+pub fn publish(event: KeyEvent) {
+    log::debug!("Publishing {} for {}", event.kind.as_str(), event.user_id);
+    SUBSCRIBERS
+        .lock()
+        .unwrap()
+        .retain(|tx| tx.send(event.clone()).is_ok());
+}
+
+/// Serves `GET /events` on its own listener. Rocket 0.4 buffers streamed bodies into fixed size chunks, which holds
+/// events back, and every open response would keep one of its fixed pool of workers busy, so each subscriber gets a
+/// thread here writing and flushing events as they happen. Like the directory routes, it needs an API token the bot
+/// issued, checked again before every event and keepalive, and it shares their per-IP `http_per_minute`.
+pub fn start_listener(addr: &str) -> std::io::Result<()> {
+    let listener: TcpListener = TcpListener::bind(addr)?;
+    log::info!("Serving key events on {}/events", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    if OPEN_STREAMS.fetch_add(1, Ordering::SeqCst) >= MAX_STREAMS {
+                        OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+                        log::warn!("Rejecting event stream, {} are open already", MAX_STREAMS);
+                        let _ = stream.write_all(
+                            b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        );
+                        continue;
+                    }
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream) {
+                            log::debug!("Event stream closed: {}", e);
+                        }
+                        OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Err(e) => log::error!("Could not accept event stream: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reads from `stream` until `deadline`, later reads fail with `TimedOut`.
+struct Deadline {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining: Duration = self.deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(io::Error::new(ErrorKind::TimedOut, "request head too slow"));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+fn serve(mut stream: TcpStream) -> std::io::Result<()> {
+    // a client that stops reading must not block the thread for good either
+    stream.set_write_timeout(Some(Duration::from_secs(2 * KEEPALIVE_SECS)))?;
+    let head = Deadline {
+        stream: stream.try_clone()?,
+        deadline: Instant::now() + Duration::from_secs(REQUEST_TIMEOUT_SECS),
+    };
+    let mut reader = BufReader::new(head.take(MAX_REQUEST_BYTES));
+    let mut request_line: String = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut token: Option<String> = None;
+    let mut real_ip: Option<String> = None;
+    let complete: bool = loop {
+        let mut header: String = String::new();
+        if reader.read_line(&mut header)? == 0 || !header.ends_with('\n') {
+            // closed or over MAX_REQUEST_BYTES before the blank line
+            break false;
+        }
+        if header.trim().is_empty() {
+            break true;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Authorization") {
+                token = value
+                    .trim()
+                    .strip_prefix("Bearer ")
+                    .map(|t| t.trim().to_string());
+            } else if name.trim().eq_ignore_ascii_case("X-Real-IP") {
+                real_ip = Some(value.trim().to_string());
+            }
+        }
+    };
+    if !complete {
+        return stream.write_all(
+            b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+    }
+
+    let client: String =
+        ratelimit::client_key(stream.peer_addr().ok().map(|a| a.ip()), real_ip.as_deref());
+    if let Err(wait) = ratelimit::check_event_stream(&client) {
+        return stream.write_all(
+            format!(
+                "HTTP/1.1 429 Too Many Requests\r\nRetry-After: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                wait.as_secs() + 1
+            )
+            .as_bytes(),
+        );
+    }
+
+    let path: &str = request_line.split_whitespace().nth(1).unwrap_or("");
+    if !request_line.starts_with("GET ") || path.split('?').next() != Some("/events") {
+        return stream.write_all(
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+    }
+    let token: String = token.unwrap_or_default();
+    if !authorized(&token) {
+        log::warn!("Rejecting unauthenticated event stream");
+        return stream.write_all(
+            b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+    }
+
+    let events: Receiver<KeyEvent> = subscribe();
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nX-Accel-Buffering: no\r\n\r\n",
+    )?;
+    stream.write_all(b": connected\n\n")?;
+    stream.flush()?;
+    loop {
+        let chunk: String = match events.recv_timeout(Duration::from_secs(KEEPALIVE_SECS)) {
+            Ok(event) => event.to_sse(),
+            Err(RecvTimeoutError::Timeout) => ": keepalive\n\n".to_string(),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        // the token may have been revoked or expired, or its user deleted, since the stream opened
+        if !authorized(&token) {
+            log::info!("Closing an event stream whose API token is no longer valid");
+            return Ok(());
+        }
+        stream.write_all(chunk.as_bytes())?;
+        stream.flush()?;
+    }
+}
+
+/// Whether `token` still authenticates a workspace member. It fails closed while the store cannot be read.
+fn authorized(token: &str) -> bool {
+    if token.is_empty() {
+        return false;
+    }
+    match tokens::authenticate(token, util::unix_timestamp()) {
+        Ok(user) => user.is_some(),
+        Err(e) => {
+            log::error!("Could not check an API token: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use crate::db::Member;
+    use std::io::Read;
+
+    fn event() -> KeyEvent {
+        KeyEvent {
+            kind: EventKind::Rotated,
+            user_id: "U1234ABC".to_string(),
+            name: "jeff".to_string(),
+            fingerprint: Some("ab:cd".to_string()),
+            revision: 7,
+        }
+    }
+
+    #[test]
+    fn test_to_sse() {
+        assert_eq!(
+            event().to_sse(),
+            "event: key-rotated\nid: 7\ndata: {\"fingerprint\":\"ab:cd\",\"name\":\"jeff\",\"revision\":7,\"user_id\":\"U1234ABC\"}\n\n"
+        );
+    }
+
+    /// A connection to `serve` that sent `request`.
+    fn connect(request: &str) -> TcpStream {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener.accept().unwrap().0));
+        let mut client: TcpStream = TcpStream::connect(addr).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+        client
+    }
+
+    #[test]
+    fn test_serve() {
+        db::init_memory();
+        db::upsert_members(&[Member::new("UEVT0001", "listener", "")]).unwrap();
+        let (token, _) = tokens::issue("UEVT0001", util::unix_timestamp()).unwrap();
+
+        let mut rejected: String = String::new();
+        connect("GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .read_to_string(&mut rejected)
+            .unwrap();
+        assert!(rejected.starts_with("HTTP/1.1 401 Unauthorized"));
+
+        let mut client: TcpStream = connect(&format!(
+            "GET /events HTTP/1.1\r\nHost: localhost\r\nauthorization: Bearer {}\r\n\r\n",
+            token
+        ));
+        let mut received: Vec<u8> = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !String::from_utf8_lossy(&received).contains(": connected") {
+            let n: usize = client.read(&mut buf).unwrap();
+            received.extend_from_slice(&buf[..n]);
+        }
+
+        publish(event());
+        while !String::from_utf8_lossy(&received).contains("\"user_id\":\"U1234ABC\"}\n\n") {
+            let n: usize = client.read(&mut buf).unwrap();
+            received.extend_from_slice(&buf[..n]);
+        }
+        let received: String = String::from_utf8(received).unwrap();
+        assert!(received.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/event-stream"));
+        assert!(received.contains("event: key-rotated\nid: 7\n"));
+    }
+
+    #[test]
+    fn test_serve_closes_revoked_stream() {
+        db::init_memory();
+        db::upsert_members(&[Member::new("UEVT0002", "revoked", "")]).unwrap();
+        let (token, _) = tokens::issue("UEVT0002", util::unix_timestamp()).unwrap();
+        let mut client: TcpStream = connect(&format!(
+            "GET /events HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\n\r\n",
+            token
+        ));
+        let mut received: Vec<u8> = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !String::from_utf8_lossy(&received).contains(": connected") {
+            let n: usize = client.read(&mut buf).unwrap();
+            received.extend_from_slice(&buf[..n]);
+        }
+
+        tokens::revoke("UEVT0002", "UEVT0002").unwrap();
+        publish(KeyEvent {
+            user_id: "UEVT0002".to_string(),
+            ..event()
+        });
+        client.read_to_end(&mut received).unwrap();
+        assert!(!String::from_utf8_lossy(&received).contains("UEVT0002"));
+    }
+
+    #[test]
+    fn test_serve_incomplete_request() {
+        let client: TcpStream = connect("GET /events HTTP/1.1\r\nHost: localhost\r\n");
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response: String = String::new();
+        (&client).read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    }
+}
@@ -5,20 +5,66 @@ extern crate rocket;
 #[macro_use]
 extern crate rocket_contrib;
 
+use std::env;
 use std::fs;
+use std::process;
 use std::thread;
 
 use simple_logger::SimpleLogger;
 
+mod admin;
+mod api;
+mod bot;
+mod challenge;
+mod cli;
+mod commands;
+mod config;
+mod crypto;
 mod db;
+mod etag;
+mod events;
+mod merkle;
+mod metrics;
+mod migrations;
+mod notify;
+mod ratelimit;
 mod server;
 mod slack;
+mod slack_api;
+mod slack_events;
+mod store;
+mod supervisor;
+mod tokens;
 mod util;
 
 fn main() {
     SimpleLogger::from_env().init().unwrap();
-    init();
-    start_services();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match cli::parse(&args) {
+        Ok(cli::Subcommand::Serve) => {
+            init();
+            if let Err(e) = config::init().and_then(|config| config.validate()) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            start_services();
+        }
+        Ok(subcommand) => {
+            init();
+            if let Err(e) = config::init() {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            if let Err(e) = cli::run(subcommand) {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    }
 }
 
 fn init() {
@@ -34,13 +80,30 @@ fn init() {
 
 fn start_services() {
     db::init().expect("Could not initialize and start the database!");
+    log::info!("Server signing key:\n{}", crypto::signing_public_key_pem());
+    supervisor::handle_signals().expect("Could not install the signal handlers!");
     start_slack_bot();
+    events::start_listener(&config::get().events_addr)
+        .expect("Could not start the key events listener!");
     server::start_server();
 }
 
 fn start_slack_bot() {
-    let server_base_url: String = util::get_env_var("SLACKRYPT_BASE_URL", "127.0.0.1:8000");
-    thread::spawn(move || {
-        slack::init(&server_base_url);
-    });
+    let config: &config::Config = config::get();
+    let server_base_url: String = config.base_url.clone();
+    let max_reconnects: u32 = config.slack.max_reconnects;
+    notify::start(
+        slack_api::SlackApi::new(&config.slack.bot_token),
+        &config.slack.security_channel,
+    );
+    if slack_events::signing_secret().is_empty() {
+        log::warn!("SLACK_SIGNING_SECRET is not set, falling back to the deprecated RTM API");
+        thread::spawn(move || {
+            slack::init(&server_base_url, max_reconnects);
+        });
+    } else {
+        thread::spawn(move || {
+            slack_events::init(&server_base_url, max_reconnects);
+        });
+    }
 }
@@ -0,0 +1,128 @@
+use sha2::{Digest, Sha256};
+
+/// Merkle tree hashing, audit paths and consistency proofs as specified in RFC 6962 section 2.1.
+/// Trees are given as the hashes of all their leaves, in log order.
+pub type Hash = [u8; 32];
+
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0x00]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// The largest power of two smaller than `n`, for `n > 1`.
+fn split_point(n: usize) -> usize {
+    let mut k: usize = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+/// MTH(D[n])
+pub fn root(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => Sha256::digest([]).into(),
+        1 => leaves[0],
+        n => {
+            let k: usize = split_point(n);
+            node_hash(&root(&leaves[..k]), &root(&leaves[k..]))
+        }
+    }
+}
+
+/// PATH(m, D[n]), the audit path proving leaf `m` is included in the tree.
+pub fn inclusion_proof(m: usize, leaves: &[Hash]) -> Vec<Hash> {
+    let n: usize = leaves.len();
+    if n <= 1 {
+        return Vec::new();
+    }
+    let k: usize = split_point(n);
+    let (mut path, sibling) = if m < k {
+        (inclusion_proof(m, &leaves[..k]), root(&leaves[k..]))
+    } else {
+        (inclusion_proof(m - k, &leaves[k..]), root(&leaves[..k]))
+    };
+    path.push(sibling);
+    path
+}
+
+/// PROOF(m, D[n]), proving the tree of the first `m` leaves is a prefix of the tree of all leaves.
+pub fn consistency_proof(m: usize, leaves: &[Hash]) -> Vec<Hash> {
+    if m == 0 || m >= leaves.len() {
+        return Vec::new();
+    }
+    subproof(m, leaves, true)
+}
+
+fn subproof(m: usize, leaves: &[Hash], complete: bool) -> Vec<Hash> {
+    let n: usize = leaves.len();
+    if m == n {
+        return if complete {
+            Vec::new()
+        } else {
+            vec![root(leaves)]
+        };
+    }
+    let k: usize = split_point(n);
+    let (mut proof, sibling) = if m <= k {
+        (subproof(m, &leaves[..k], complete), root(&leaves[k..]))
+    } else {
+        (subproof(m - k, &leaves[k..], false), root(&leaves[..k]))
+    };
+    proof.push(sibling);
+    proof
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: usize) -> Vec<Hash> {
+        (0..n)
+            .map(|i| leaf_hash(format!("U{},key", i).as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn test_root() {
+        let l: Vec<Hash> = leaves(3);
+        let expected: Hash = node_hash(&node_hash(&l[0], &l[1]), &l[2]);
+        assert_eq!(expected, root(&l));
+        assert_eq!(l[0], root(&l[..1]));
+    }
+
+    #[test]
+    fn test_inclusion_proof() {
+        let l: Vec<Hash> = leaves(7);
+        // d in the RFC 6962 example tree
+        assert_eq!(
+            inclusion_proof(3, &l),
+            vec![l[2], node_hash(&l[0], &l[1]), root(&l[4..])]
+        );
+        assert!(inclusion_proof(0, &l[..1]).is_empty());
+    }
+
+    #[test]
+    fn test_consistency_proof() {
+        let l: Vec<Hash> = leaves(7);
+        let k = node_hash(&l[4], &l[5]);
+        // PROOF(3, D[7]) = [c, d, g, l] in the RFC 6962 example tree
+        assert_eq!(
+            consistency_proof(3, &l),
+            vec![l[2], l[3], node_hash(&l[0], &l[1]), node_hash(&k, &l[6])]
+        );
+        // PROOF(4, D[7]) = [l]
+        assert_eq!(consistency_proof(4, &l), vec![node_hash(&k, &l[6])]);
+        assert!(consistency_proof(7, &l).is_empty());
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds in seconds of the request latency buckets, Prometheus adds `+Inf`.
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+/// The latencies of one route. `buckets[i]` counts the requests no slower than `LATENCY_BUCKETS[i]`.
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Requests by method, route and status.
+static REQUESTS: Mutex<BTreeMap<(String, String, u16), u64>> = Mutex::new(BTreeMap::new());
+
+/// Latencies by method and route.
+static LATENCIES: Mutex<BTreeMap<(String, String), Histogram>> = Mutex::new(BTreeMap::new());
+
+static DB_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+static BOT_RECONNECTS: AtomicU64 = AtomicU64::new(0);
+
+/// Rejected requests by rate limit, see `ratelimit`.
+static RATE_LIMITED: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// Counts a served request. `route` is the matched route's pattern, e.g. `/pubkey/users/<user_id>`,
+/// so the number of series stays bounded whatever paths clients ask for.
+pub fn observe_request(method: &str, route: &str, status: u16, elapsed: Duration) {
+    *REQUESTS
+        .lock()
+        .unwrap()
+        .entry((method.to_string(), route.to_string(), status))
+        .or_insert(0) += 1;
+
+    let seconds: f64 = elapsed.as_secs_f64();
+    let mut latencies = LATENCIES.lock().unwrap();
+    let histogram: &mut Histogram = latencies
+        .entry((method.to_string(), route.to_string()))
+        .or_default();
+    for (bucket, bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+        if seconds <= *bound {
+            *bucket += 1;
+        }
+    }
+    histogram.count += 1;
+    histogram.sum += seconds;
+}
+
+/// Counts a failed database operation, called where backend errors become `StoreError`s.
+pub fn db_error() {
+    DB_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a reconnect of the Slack bot.
+pub fn bot_reconnect() {
+    BOT_RECONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts a request or bot message rejected by the rate limit `scope`.
+pub fn rate_limited(scope: &str) {
+    *RATE_LIMITED
+        .lock()
+        .unwrap()
+        .entry(scope.to_string())
+        .or_insert(0) += 1;
+}
+
+/// A value read when scraped, e.g. the number of registered keys.
+pub struct Gauge {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub value: i64,
+}
+
+/// A label value with `\`, `"` and newlines escaped as the text format requires.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Every metric and `gauges` in the Prometheus text exposition format (version 0.0.4).
+pub fn render(gauges: &[Gauge]) -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "slackrypt_http_requests_total",
+        "counter",
+        "HTTP requests by route and status.",
+    );
+    for ((method, route, status), count) in REQUESTS.lock().unwrap().iter() {
+        writeln!(
+            out,
+            "slackrypt_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+            method,
+            escape(route),
+            status,
+            count
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "slackrypt_http_request_duration_seconds",
+        "histogram",
+        "HTTP request latencies by route.",
+    );
+    for ((method, route), histogram) in LATENCIES.lock().unwrap().iter() {
+        let labels: String = format!("method=\"{}\",route=\"{}\"", method, escape(route));
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            writeln!(
+                out,
+                "slackrypt_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, bound, bucket
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "slackrypt_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            labels, histogram.count
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "slackrypt_http_request_duration_seconds_sum{{{}}} {}",
+            labels, histogram.sum
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "slackrypt_http_request_duration_seconds_count{{{}}} {}",
+            labels, histogram.count
+        )
+        .unwrap();
+    }
+
+    header(
+        &mut out,
+        "slackrypt_db_errors_total",
+        "counter",
+        "Failed database operations.",
+    );
+    writeln!(
+        out,
+        "slackrypt_db_errors_total {}",
+        DB_ERRORS.load(Ordering::Relaxed)
+    )
+    .unwrap();
+
+    header(
+        &mut out,
+        "slackrypt_bot_reconnects_total",
+        "counter",
+        "Reconnects of the Slack bot since the server started.",
+    );
+    writeln!(
+        out,
+        "slackrypt_bot_reconnects_total {}",
+        BOT_RECONNECTS.load(Ordering::Relaxed)
+    )
+    .unwrap();
+
+    header(
+        &mut out,
+        "slackrypt_rate_limited_total",
+        "counter",
+        "Requests and bot messages rejected by rate limits.",
+    );
+    for (scope, count) in RATE_LIMITED.lock().unwrap().iter() {
+        writeln!(
+            out,
+            "slackrypt_rate_limited_total{{scope=\"{}\"}} {}",
+            scope, count
+        )
+        .unwrap();
+    }
+
+    for gauge in gauges {
+        header(&mut out, gauge.name, "gauge", gauge.help);
+        writeln!(out, "{} {}", gauge.name, gauge.value).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        observe_request(
+            "GET",
+            "/metrics-test/<user_id>",
+            200,
+            Duration::from_millis(20),
+        );
+        observe_request(
+            "GET",
+            "/metrics-test/<user_id>",
+            404,
+            Duration::from_millis(2),
+        );
+        let rendered: String = render(&[Gauge {
+            name: "slackrypt_registered_keys",
+            help: "Users with a current key.",
+            value: 7,
+        }]);
+        let has = |line: &str| rendered.lines().any(|l| l == line);
+
+        assert!(has(
+            "slackrypt_http_requests_total{method=\"GET\",route=\"/metrics-test/<user_id>\",status=\"404\"} 1"
+        ));
+        let labels: &str = "method=\"GET\",route=\"/metrics-test/<user_id>\"";
+        assert!(has(&format!(
+            "slackrypt_http_request_duration_seconds_bucket{{{},le=\"0.001\"}} 0",
+            labels
+        )));
+        assert!(has(&format!(
+            "slackrypt_http_request_duration_seconds_bucket{{{},le=\"0.005\"}} 1",
+            labels
+        )));
+        assert!(has(&format!(
+            "slackrypt_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 2",
+            labels
+        )));
+        assert!(has(&format!(
+            "slackrypt_http_request_duration_seconds_count{{{}}} 2",
+            labels
+        )));
+        assert!(has("# TYPE slackrypt_registered_keys gauge"));
+        assert!(has("slackrypt_registered_keys 7"));
+        assert!(rendered.contains("\nslackrypt_db_errors_total "));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
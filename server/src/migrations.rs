@@ -0,0 +1,239 @@
+use rusqlite::{ffi, params, Connection, Error, Result};
+
+use crate::store::sqlite;
+
+/// A forward-only schema change. `version` is its position in `MIGRATIONS`, starting at 1.
+/// `backfill` runs in the same transaction, after `sql`, for data that cannot be moved with SQL alone.
+pub struct Migration {
+    pub description: &'static str,
+    pub sql: &'static str,
+    pub backfill: Option<fn(&Connection) -> Result<()>>,
+}
+
+/// Every schema change ever released, in order. Never edit or reorder a released migration, append a new one.
+/// The `IF NOT EXISTS` clauses let databases created before `schema_version` existed migrate cleanly.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "create users",
+        sql: "CREATE TABLE IF NOT EXISTS users (
+                  id              INTEGER PRIMARY KEY,
+                  user_id         TEXT UNIQUE NOT NULL,
+                  name            TEXT NOT NULL,
+                  pubkey          TEXT NOT NULL
+                  );",
+        backfill: None,
+    },
+    Migration {
+        description: "create directory_serial",
+        sql: "CREATE TABLE IF NOT EXISTS directory_serial (
+                  id              INTEGER PRIMARY KEY CHECK (id = 0),
+                  serial          INTEGER NOT NULL
+                  );
+              INSERT OR IGNORE INTO directory_serial (id, serial) VALUES (0, 0);",
+        backfill: None,
+    },
+    Migration {
+        description: "create log_leaves",
+        sql: "CREATE TABLE IF NOT EXISTS log_leaves (
+                  leaf_index      INTEGER PRIMARY KEY,
+                  user_id         TEXT NOT NULL,
+                  pubkey          TEXT NOT NULL,
+                  leaf_hash       BLOB NOT NULL,
+                  created_at      INTEGER NOT NULL
+                  );",
+        backfill: Some(sqlite::backfill_log),
+    },
+    Migration {
+        description: "create pending_keys",
+        sql: "CREATE TABLE IF NOT EXISTS pending_keys (
+                  user_id         TEXT PRIMARY KEY,
+                  name            TEXT NOT NULL,
+                  pubkey          TEXT NOT NULL,
+                  nonce_hash      TEXT NOT NULL,
+                  created_at      INTEGER NOT NULL
+                  );",
+        backfill: None,
+    },
+    Migration {
+        description: "create keys",
+        sql: "CREATE TABLE IF NOT EXISTS keys (
+                  id                  INTEGER PRIMARY KEY,
+                  user_id             TEXT NOT NULL,
+                  pubkey              TEXT NOT NULL,
+                  fingerprint         TEXT,
+                  created_at          INTEGER NOT NULL,
+                  revoked_at          INTEGER,
+                  revocation_reason   TEXT
+                  );",
+        backfill: Some(sqlite::backfill_keys),
+    },
+    Migration {
+        description: "add users.real_name",
+        sql: "ALTER TABLE users ADD COLUMN real_name TEXT NOT NULL DEFAULT '';",
+        backfill: None,
+    },
+    Migration {
+        description: "add users.revision",
+        sql: "ALTER TABLE users ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;
+              CREATE INDEX IF NOT EXISTS users_revision ON users (revision);",
+        backfill: None,
+    },
+    Migration {
+        description: "add users.deleted",
+        sql: "ALTER TABLE users ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;",
+        backfill: None,
+    },
+    Migration {
+        description: "create audit_events",
+        sql: "CREATE TABLE IF NOT EXISTS audit_events (
+                  id              INTEGER PRIMARY KEY AUTOINCREMENT,
+                  created_at      INTEGER NOT NULL,
+                  actor           TEXT NOT NULL,
+                  action          TEXT NOT NULL,
+                  subject         TEXT NOT NULL,
+                  detail          TEXT NOT NULL
+                  );
+              CREATE INDEX IF NOT EXISTS audit_events_subject ON audit_events (subject, created_at);",
+        backfill: None,
+    },
+    Migration {
+        description: "chain audit_events",
+        sql: "ALTER TABLE audit_events ADD COLUMN prev_hash TEXT NOT NULL DEFAULT '';
+              ALTER TABLE audit_events ADD COLUMN hash TEXT NOT NULL DEFAULT '';",
+        backfill: Some(sqlite::backfill_audit_chain),
+    },
+    Migration {
+        description: "make audit_events append-only",
+        sql: "CREATE TRIGGER IF NOT EXISTS audit_events_no_update BEFORE UPDATE ON audit_events
+              BEGIN SELECT RAISE(ABORT, 'audit_events is append-only'); END;
+              CREATE TRIGGER IF NOT EXISTS audit_events_no_delete BEFORE DELETE ON audit_events
+              BEGIN SELECT RAISE(ABORT, 'audit_events is append-only'); END;",
+        backfill: None,
+    },
+    Migration {
+        description: "create api_tokens",
+        sql: "CREATE TABLE IF NOT EXISTS api_tokens (
+                  token_hash      TEXT PRIMARY KEY,
+                  user_id         TEXT NOT NULL,
+                  created_at      INTEGER NOT NULL,
+                  expires_at      INTEGER NOT NULL,
+                  revoked_at      INTEGER
+                  );
+              CREATE INDEX IF NOT EXISTS api_tokens_user_id ON api_tokens (user_id);",
+        backfill: None,
+    },
+    Migration {
+        description: "index users.name",
+        sql: "CREATE INDEX IF NOT EXISTS users_name ON users (name);",
+        backfill: None,
+    },
+];
+
+pub fn schema_version(conn: &Connection) -> Result<usize> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+                  version         INTEGER NOT NULL,
+                  applied_at      INTEGER NOT NULL
+                  )",
+        params![],
+    )?;
+    let version: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        params![],
+        |row| row.get(0),
+    )?;
+    Ok(version as usize)
+}
+
+/// Applies every migration newer than the database's `schema_version`, each in its own transaction.
+/// Any failure is returned as is, including a database written by a newer server than this one.
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    let current: usize = schema_version(conn)?;
+    if current > MIGRATIONS.len() {
+        return Err(Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_MISMATCH),
+            Some(format!(
+                "Database schema version {} is newer than the {} migrations this server knows",
+                current,
+                MIGRATIONS.len()
+            )),
+        ));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(current) {
+        let version: usize = i + 1;
+        log::info!("Applying migration {}: {}", version, migration.description);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)?;
+        if let Some(backfill) = migration.backfill {
+            backfill(&tx)?;
+        }
+        tx.execute(
+            "INSERT INTO schema_version (version, applied_at) VALUES (?1, strftime('%s', 'now'))",
+            params![version as i64],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_fresh_database() {
+        let mut conn: Connection = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+
+        // migrating again is a no-op
+        migrate(&mut conn).unwrap();
+        let applied: i64 = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(applied as usize, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn test_migrate_legacy_database() {
+        let mut conn: Connection = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (
+                  id              INTEGER PRIMARY KEY,
+                  user_id         TEXT UNIQUE NOT NULL,
+                  name            TEXT NOT NULL,
+                  pubkey          TEXT NOT NULL
+                  );
+             INSERT INTO users (user_id, name, pubkey) VALUES ('U1234ABC', 'jeff', 'KEY');
+             INSERT INTO users (user_id, name, pubkey) VALUES ('U5678DEF', 'rade', '');",
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+
+        let logged: i64 = conn
+            .query_row("SELECT COUNT(*) FROM log_leaves", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(logged, 1);
+        let keys: i64 = conn
+            .query_row("SELECT COUNT(*) FROM keys", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(keys, 1);
+    }
+
+    #[test]
+    fn test_migrate_newer_database() {
+        let mut conn: Connection = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, applied_at) VALUES (?1, 0)",
+            params![MIGRATIONS.len() as i64 + 1],
+        )
+        .unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use serde_json::json;
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use crate::db;
+use crate::db::{AuditEvent, AuditQuery};
+use crate::events::{self, EventKind, KeyEvent};
+use crate::slack_api::{SlackApi, SlackApiError};
+use crate::util;
+
+/// A user gets at most this many key-change DMs per `NOTIFY_WINDOW_SECS`, so a flood of changes cannot be used
+/// to spam them. Suppressed notices still reach the security channel and the audit log.
+const NOTIFY_LIMIT: usize = 3;
+
+const NOTIFY_WINDOW_SECS: i64 = 3_600;
+
+/// Audit log actions, the subject is the key owner.
+pub const NOTIFIED: &str = "key-change-notified";
+pub const ANNOUNCED: &str = "key-change-announced";
+pub const SUPPRESSED: &str = "key-change-notification-suppressed";
+pub const FAILED: &str = "key-change-notification-failed";
+
+/// The DM telling the owner their key changed.
+pub fn owner_message(event: &KeyEvent) -> String {
+    let fingerprint: &str = event.fingerprint.as_deref().unwrap_or("unknown");
+    match event.kind {
+        EventKind::Added => format!(
+            "A Slackrypt public key with the fingerprint `{}` was registered for you. If this wasn't you, \
+             revoke it right away with `/slackrypt revoke confirm` and tell your Slack admins, your account may be compromised.",
+            fingerprint
+        ),
+        EventKind::Rotated => format!(
+            "Your Slackrypt key was changed, the new fingerprint is `{}`. If this wasn't you, \
+             revoke it right away with `/slackrypt revoke confirm` and tell your Slack admins, your account may be compromised.",
+            fingerprint
+        ),
+        EventKind::Revoked => format!(
+            "Your Slackrypt key `{}` was revoked, nobody can encrypt to you until you register a new one. \
+             If this wasn't you, tell your Slack admins, your account may be compromised.",
+            fingerprint
+        ),
+    }
+}
+
+/// The post to the security channel. It names the user without mentioning them.
+pub fn channel_message(event: &KeyEvent) -> String {
+    format!(
+        "{} for {} ({}): fingerprint `{}`, directory revision {}",
+        event.kind.as_str(),
+        event.name,
+        event.user_id,
+        event.fingerprint.as_deref().unwrap_or("unknown"),
+        event.revision
+    )
+}
+
+/// Whether the owner already got `NOTIFY_LIMIT` DMs within the window ending at `now`. While the audit log
+/// cannot be read they are not limited, a missed key-change notice is worse than one DM too many.
+fn is_rate_limited(user_id: &str, now: i64) -> bool {
+    let notified: Vec<AuditEvent> = match db::get_audit_events(&AuditQuery {
+        subject: Some(user_id.to_string()),
+        action: Some(NOTIFIED.to_string()),
+        since: Some(now - NOTIFY_WINDOW_SECS),
+        limit: Some(NOTIFY_LIMIT),
+        ..AuditQuery::default()
+    }) {
+        Ok(notified) => notified,
+        Err(e) => {
+            log::error!("Could not count the notices {} got: {}", user_id, e);
+            return false;
+        }
+    };
+    notified.len() >= NOTIFY_LIMIT
+}
+
+fn audit(action: &str, event: &KeyEvent, to: &str, error: Option<String>) {
+    let detail: serde_json::Value = json!({
+        "event": event.kind.as_str(),
+        "fingerprint": event.fingerprint,
+        "revision": event.revision,
+        "to": to,
+        "error": error,
+    });
+    if let Err(e) = db::record_audit_event("bot", action, &event.user_id, &detail) {
+        log::error!("Could not record {} for {}: {}", action, event.user_id, e);
+    }
+}
+
+/// DMs the owner about `event` unless rate limited and posts it to `security_channel` if one is set,
+/// every notice is audited. `post` sends a message to a channel or user id.
+fn notify<F>(post: F, security_channel: &str, event: &KeyEvent, now: i64)
+where
+    F: Fn(&str, &str) -> Result<(), SlackApiError>,
+{
+    if is_rate_limited(&event.user_id, now) {
+        log::warn!(
+            "Not notifying {} of {}, they got {} notices within {}s",
+            event.user_id,
+            event.kind.as_str(),
+            NOTIFY_LIMIT,
+            NOTIFY_WINDOW_SECS
+        );
+        audit(SUPPRESSED, event, "dm", None);
+    } else {
+        match post(&event.user_id, &owner_message(event)) {
+            Ok(()) => audit(NOTIFIED, event, "dm", None),
+            Err(e) => {
+                log::error!("Could not notify {}: {}", event.user_id, e);
+                audit(FAILED, event, "dm", Some(e.to_string()));
+            }
+        }
+    }
+
+    if !security_channel.is_empty() {
+        match post(security_channel, &channel_message(event)) {
+            Ok(()) => audit(ANNOUNCED, event, security_channel, None),
+            Err(e) => {
+                log::error!("Could not post to {}: {}", security_channel, e);
+                audit(FAILED, event, security_channel, Some(e.to_string()));
+            }
+        }
+    }
+}
+
+/// Notifies key owners of every key change from now on, in a thread of its own.
+pub fn start(api: SlackApi, security_channel: &str) {
+    let events: Receiver<KeyEvent> = events::subscribe();
+    let security_channel: String = security_channel.to_string();
+    thread::spawn(move || {
+        for event in events {
+            notify(
+                |channel, text| api.post_message(channel, text),
+                &security_channel,
+                &event,
+                util::unix_timestamp(),
+            );
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn event(kind: EventKind) -> KeyEvent {
+        KeyEvent {
+            kind,
+            user_id: "UNTF0001".to_string(),
+            name: "jeff".to_string(),
+            fingerprint: Some("ab:cd".to_string()),
+            revision: 7,
+        }
+    }
+
+    #[test]
+    fn test_messages() {
+        assert!(owner_message(&event(EventKind::Rotated))
+            .starts_with("Your Slackrypt key was changed, the new fingerprint is `ab:cd`."));
+        assert!(owner_message(&event(EventKind::Revoked)).contains("`ab:cd` was revoked"));
+        assert_eq!(
+            channel_message(&event(EventKind::Added)),
+            "key-added for jeff (UNTF0001): fingerprint `ab:cd`, directory revision 7"
+        );
+    }
+
+    #[test]
+    fn test_notify() {
+        db::init_memory();
+        let sent: RefCell<Vec<String>> = RefCell::new(Vec::new());
+        let post = |channel: &str, _text: &str| {
+            sent.borrow_mut().push(channel.to_string());
+            if channel == "#down" {
+                Err(SlackApiError::new("channel_not_found"))
+            } else {
+                Ok(())
+            }
+        };
+        let now: i64 = util::unix_timestamp();
+        for _ in 0..NOTIFY_LIMIT + 1 {
+            notify(post, "#security", &event(EventKind::Rotated), now);
+        }
+        notify(post, "#down", &event(EventKind::Revoked), now);
+
+        // the owner got NOTIFY_LIMIT DMs, the channel heard of every change
+        let dms: usize = sent.borrow().iter().filter(|c| *c == "UNTF0001").count();
+        assert_eq!(dms, NOTIFY_LIMIT);
+        assert_eq!(sent.borrow().len(), NOTIFY_LIMIT + (NOTIFY_LIMIT + 1) + 1);
+
+        let audit: Vec<AuditEvent> = db::get_audit_events(&AuditQuery {
+            subject: Some("UNTF0001".to_string()),
+            ..AuditQuery::default()
+        })
+        .unwrap();
+        let actions: Vec<&str> = audit.iter().map(|e| e.action.as_str()).collect();
+        assert_eq!(
+            actions.iter().filter(|a| **a == NOTIFIED).count(),
+            NOTIFY_LIMIT
+        );
+        assert_eq!(actions.iter().filter(|a| **a == SUPPRESSED).count(), 2);
+        assert_eq!(actions.last(), Some(&FAILED));
+        assert!(audit.last().unwrap().detail.contains("channel_not_found"));
+
+        // the window has passed
+        assert!(is_rate_limited("UNTF0001", now));
+        assert!(!is_rate_limited("UNTF0001", now + NOTIFY_WINDOW_SECS + 1));
+    }
+}
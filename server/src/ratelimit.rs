@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+
+use crate::config::{self, RateLimitConfig};
+use crate::metrics;
+
+/// Clients tracked at most per limiter. When a new client would go beyond it, the clients idle for a whole window are
+/// forgotten, they are back to a full allowance anyway. If that leaves more than half of `MAX_TRACKED`, e.g. during a
+/// flood from many addresses, the least recently seen are forgotten too, so sweeping stays rare.
+const MAX_TRACKED: usize = 10_000;
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+const HOUR: Duration = Duration::from_secs(3_600);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket per client: a burst of up to `capacity` requests, after which the allowance comes back evenly
+/// over `window`. A capacity of 0 disables the limit.
+pub struct RateLimiter {
+    capacity: u32,
+    window: Duration,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, window: Duration) -> RateLimiter {
+        RateLimiter {
+            capacity,
+            window,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes one request from `client`'s allowance, or says how long until they may try again.
+    pub fn check(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let capacity: f64 = f64::from(self.capacity);
+        let per_second: f64 = capacity / self.window.as_secs_f64();
+        let refill = |bucket: &Bucket| -> f64 {
+            let elapsed: f64 = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * per_second).min(capacity)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED && !buckets.contains_key(client) {
+            forget(&mut buckets, now, self.window);
+        }
+        let bucket: &mut Bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+/// Drops the buckets idle for `window`, then the least recently used ones down to half of `MAX_TRACKED`.
+fn forget(buckets: &mut HashMap<String, Bucket>, now: Instant, window: Duration) {
+    buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < window);
+    let keep: usize = MAX_TRACKED / 2;
+    if buckets.len() > keep {
+        let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+        let evict: usize = buckets.len() - keep;
+        let (_, cutoff, _) = updated.select_nth_unstable(evict - 1);
+        let cutoff: Instant = *cutoff;
+        let mut evicted: usize = 0;
+        buckets.retain(|_, bucket| {
+            if evicted < evict && bucket.updated <= cutoff {
+                evicted += 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// The limiters configured in `[rate_limit]`, created on first use.
+struct Limiters {
+    http: RateLimiter,
+    directory: RateLimiter,
+    bot_commands: RateLimiter,
+    key_submissions: RateLimiter,
+}
+
+static LIMITERS: OnceLock<Limiters> = OnceLock::new();
+
+/// The configured `trusted_proxies`, parsed on first use.
+static TRUSTED_PROXIES: OnceLock<Vec<IpAddr>> = OnceLock::new();
+
+fn limiters() -> &'static Limiters {
+    LIMITERS.get_or_init(|| {
+        let limits: &RateLimitConfig = &config::get().rate_limit;
+        Limiters {
+            http: RateLimiter::new(limits.http_per_minute, MINUTE),
+            directory: RateLimiter::new(limits.directory_per_minute, MINUTE),
+            bot_commands: RateLimiter::new(limits.bot_commands_per_minute, MINUTE),
+            key_submissions: RateLimiter::new(limits.key_submissions_per_hour, HOUR),
+        }
+    })
+}
+
+fn check(limiter: &RateLimiter, scope: &str, client: &str) -> Result<(), Duration> {
+    let admitted: Result<(), Duration> = limiter.check(client, Instant::now());
+    if admitted.is_err() {
+        log::warn!("Rate limiting {} on {}", client, scope);
+        metrics::rate_limited(scope);
+    }
+    admitted
+}
+
+/// A bot command of the Slack user `user_id`, see `Bot::command`.
+pub fn check_bot_command(user_id: &str) -> Result<(), Duration> {
+    check(&limiters().bot_commands, "bot-command", user_id)
+}
+
+/// A pasted or `POST /api/v1/keys` submitted public key, or a challenge answer, of the Slack user `user_id`.
+pub fn check_key_submission(user_id: &str) -> Result<(), Duration> {
+    check(&limiters().key_submissions, "key-submission", user_id)
+}
+
+/// A connection to the `/events` listener, which counts against `http_per_minute` like the routes.
+pub fn check_event_stream(client: &str) -> Result<(), Duration> {
+    check(&limiters().http, "events", client)
+}
+
+/// The reply to a Slack user over a limit.
+pub fn slow_down(wait: Duration) -> String {
+    format!(
+        "You're going too fast, please wait {} seconds and try again.",
+        wait.as_secs() + 1
+    )
+}
+
+/// Seconds a throttled client should wait, for the `429` catcher's `Retry-After`.
+pub struct RetryAfter(pub u64);
+
+/// The IP a request came from: the peer's, unless the peer is one of `trusted` and says whose request it forwards
+/// in `X-Real-IP`. Anyone else could send a new `X-Real-IP` with every request to dodge the limits.
+pub fn client_ip(
+    trusted: &[IpAddr],
+    remote: Option<IpAddr>,
+    real_ip: Option<&str>,
+) -> Option<IpAddr> {
+    match (
+        remote,
+        real_ip.and_then(|ip| ip.trim().parse::<IpAddr>().ok()),
+    ) {
+        (Some(remote), Some(real_ip)) if trusted.contains(&remote) => Some(real_ip),
+        (remote, _) => remote,
+    }
+}
+
+/// The key a client's allowance is kept under, see `client_ip`.
+pub fn client_key(remote: Option<IpAddr>, real_ip: Option<&str>) -> String {
+    let trusted: &[IpAddr] = TRUSTED_PROXIES.get_or_init(|| {
+        config::get()
+            .trusted_proxies()
+            .iter()
+            .filter_map(|ip| ip.parse().ok())
+            .collect()
+    });
+    match client_ip(trusted, remote, real_ip) {
+        Some(ip) => ip.to_string(),
+        None => String::from("unknown"),
+    }
+}
+
+fn admit(request: &Request, limiter: &RateLimiter, scope: &str) -> request::Outcome<(), ()> {
+    let client: String = client_key(
+        request.remote().map(|addr| addr.ip()),
+        request.headers().get_one("X-Real-IP"),
+    );
+    match check(limiter, scope, &client) {
+        Ok(()) => Outcome::Success(()),
+        Err(wait) => {
+            request.local_cache(|| RetryAfter(wait.as_secs() + 1));
+            Outcome::Failure((Status::TooManyRequests, ()))
+        }
+    }
+}
+
+/// Admits a request within the per-IP `http_per_minute`, `429 Too Many Requests` otherwise.
+/// Behind one of the `trusted_proxies` the client is taken from `X-Real-IP`.
+pub struct HttpLimit;
+
+impl<'a, 'r> FromRequest<'a, 'r> for HttpLimit {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        admit(request, &limiters().http, "http").map(|()| HttpLimit)
+    }
+}
+
+/// The stricter per-IP `directory_per_minute` of the routes that list the whole directory.
+pub struct DirectoryLimit;
+
+impl<'a, 'r> FromRequest<'a, 'r> for DirectoryLimit {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        admit(request, &limiters().directory, "directory").map(|()| DirectoryLimit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(3, MINUTE);
+        let start: Instant = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check("10.0.0.1", start), Ok(()));
+        }
+        let wait: Duration = limiter.check("10.0.0.1", start).err().unwrap();
+        assert_eq!(wait.as_secs(), 20);
+        // others are not affected
+        assert_eq!(limiter.check("10.0.0.2", start), Ok(()));
+        // one request comes back every 20 seconds
+        assert!(limiter
+            .check("10.0.0.1", start + Duration::from_secs(19))
+            .is_err());
+        assert_eq!(
+            limiter.check("10.0.0.1", start + Duration::from_secs(21)),
+            Ok(())
+        );
+        assert!(limiter
+            .check("10.0.0.1", start + Duration::from_secs(22))
+            .is_err());
+
+        let unlimited = RateLimiter::new(0, MINUTE);
+        for _ in 0..100 {
+            assert_eq!(unlimited.check("10.0.0.1", start), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxy: IpAddr = "127.0.0.1".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        assert_eq!(
+            client_ip(&[proxy], Some(proxy), Some("203.0.113.7")),
+            Some(client)
+        );
+        assert_eq!(client_ip(&[proxy], Some(proxy), None), Some(proxy));
+        assert_eq!(client_ip(&[proxy], Some(proxy), Some("junk")), Some(proxy));
+        // a client cannot pick its own address
+        assert_eq!(
+            client_ip(&[proxy], Some(client), Some("198.51.100.1")),
+            Some(client)
+        );
+        assert_eq!(
+            client_ip(&[], Some(proxy), Some("203.0.113.7")),
+            Some(proxy)
+        );
+        assert_eq!(client_ip(&[proxy], None, Some("203.0.113.7")), None);
+    }
+
+    #[test]
+    fn test_forget_idle_clients() {
+        let limiter = RateLimiter::new(1, MINUTE);
+        let start: Instant = Instant::now();
+        for i in 0..MAX_TRACKED {
+            limiter.check(&i.to_string(), start).unwrap();
+        }
+        limiter
+            .check("10.0.0.1", start + Duration::from_secs(61))
+            .unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_flood_of_clients() {
+        let limiter = RateLimiter::new(2, MINUTE);
+        let start: Instant = Instant::now();
+        for i in 0..3 * MAX_TRACKED {
+            let now: Instant = start + Duration::from_millis(i as u64);
+            limiter.check(&i.to_string(), now).unwrap();
+            // every bucket is partially drained, none has been idle for a window
+            assert!(limiter.buckets.lock().unwrap().len() <= MAX_TRACKED);
+        }
+        // the most recent clients are still limited
+        let now: Instant = start + Duration::from_millis(3 * MAX_TRACKED as u64);
+        let last: String = (3 * MAX_TRACKED - 1).to_string();
+        limiter.check(&last, now).unwrap();
+        assert!(limiter.check(&last, now).is_err());
+    }
+}
@@ -1,31 +1,495 @@
+use std::io::Cursor;
+use std::time::Instant;
+
+use rocket::data::Data;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::response::content::Content;
+use rocket::response::status::Custom;
+use rocket::{Request, Response};
 use rocket_contrib::json::JsonValue;
 
+use crate::admin;
+use crate::api;
+use crate::config;
+use crate::crypto;
 use crate::db;
+use crate::etag::{self, IfNoneMatch, Tagged};
+use crate::merkle;
+use crate::metrics::{self, Gauge};
+use crate::ratelimit::{DirectoryLimit, HttpLimit, RetryAfter};
+use crate::slack_events::{self, Callback, SlackRequest, SlashCommand};
+use crate::store::StoreError;
+use crate::supervisor::{self, BotState, Health};
+use crate::tokens::ApiUser;
 use crate::util;
 
 pub fn start_server() {
     log::info!("Starting HTTP service...");
     rocket::ignite()
-        .mount("/", routes![init_sh, pubkey_users])
+        .attach(RequestMetrics)
+        .register(catchers![too_many_requests])
+        .mount(
+            "/",
+            routes![
+                init_sh,
+                server_pubkey,
+                bot_health,
+                healthz,
+                readyz,
+                metrics_text,
+                pubkey_users,
+                pubkey_user,
+                pubkey_user_history,
+                pubkey_fingerprint,
+                pubkey_directory,
+                changes,
+                log_sth,
+                log_entries,
+                log_inclusion_proof,
+                log_consistency_proof,
+                slack_events_callback,
+                slack_command
+            ],
+        )
+        .mount("/api/v1", api::routes())
+        .mount("/admin/v1", admin::routes())
         .launch();
 }
 
 /// curl -H "Content-Type: text/plain" http://127.0.0.1:8000/init.sh
 #[get("/init.sh")]
-fn init_sh() -> String {
+fn init_sh(_limit: HttpLimit) -> String {
     log::debug!("init_sh() entering...");
-    let server_base_url: String = util::get_env_var("SLACKRYPT_BASE_URL", "127.0.0.1:8000");
-    util::get_init_sh_cmd(format!("https://{}", &server_base_url).as_str())
+    let config: &config::Config = config::get();
+    util::get_init_sh_cmd(
+        format!("https://{}", config.base_url).as_str(),
+        &config.events_url(),
+    )
+}
+
+/// curl -H "Content-Type: text/plain" http://127.0.0.1:8000/server.pem.pub
+#[get("/server.pem.pub")]
+fn server_pubkey(_limit: HttpLimit) -> String {
+    log::debug!("server_pubkey() entering...");
+    crypto::signing_public_key_pem()
+}
+
+/// curl -H "Content-Type: application/json" http://127.0.0.1:8000/health/bot
+///
+/// The Slack bot's connection, `503 Service Unavailable` while it is not connected.
+#[get("/health/bot")]
+fn bot_health() -> Custom<JsonValue> {
+    log::debug!("bot_health() entering...");
+    let health: Health = supervisor::health();
+    let status: Status = match health.state {
+        BotState::Connected => Status::Ok,
+        _ => Status::ServiceUnavailable,
+    };
+    Custom(
+        status,
+        json!({
+            "state": health.state.as_str(),
+            "since": health.since,
+            "reconnects": health.reconnects,
+            "last_error": health.last_error,
+        }),
+    )
+}
+
+/// When a request arrived, cached on the request for `RequestMetrics`.
+struct RequestStart(Instant);
+
+/// Counts every request and its latency by method, route and status for `/metrics`.
+struct RequestMetrics;
+
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let start: &RequestStart = request.local_cache(|| RequestStart(Instant::now()));
+        let route: String = match request.route() {
+            Some(route) => route.uri.path().to_string(),
+            None => String::from("unmatched"),
+        };
+        metrics::observe_request(
+            request.method().as_str(),
+            &route,
+            response.status().code,
+            start.0.elapsed(),
+        );
+    }
 }
 
-/// curl -H "Content-Type: application/json" http://127.0.0.1:8000/pubkey/users
+/// A request over a rate limit, with how long to wait in `Retry-After`, see `ratelimit`.
+#[catch(429)]
+fn too_many_requests(request: &Request) -> Response<'static> {
+    let retry_after: &RetryAfter = request.local_cache(|| RetryAfter(60));
+    let body: String = json!({
+        "error": "Too many requests",
+        "retry_after": retry_after.0,
+    })
+    .to_string();
+    Response::build()
+        .status(Status::TooManyRequests)
+        .header(ContentType::JSON)
+        .raw_header("Retry-After", retry_after.0.to_string())
+        .sized_body(Cursor::new(body))
+        .finalize()
+}
+
+/// curl http://127.0.0.1:8000/healthz
+///
+/// Liveness: the process is up and serving requests.
+#[get("/healthz")]
+fn healthz() -> &'static str {
+    "ok"
+}
+
+/// curl http://127.0.0.1:8000/readyz
+///
+/// Readiness: the database answers and the Slack bot is connected, `503 Service Unavailable` otherwise.
+#[get("/readyz")]
+fn readyz() -> Custom<JsonValue> {
+    log::debug!("readyz() entering...");
+    let database: Result<i64, String> = db::get_directory_serial().map_err(|e| e.to_string());
+    let bot: BotState = supervisor::health().state;
+    let ready: bool = database.is_ok() && bot == BotState::Connected;
+    Custom(
+        if ready {
+            Status::Ok
+        } else {
+            Status::ServiceUnavailable
+        },
+        json!({
+            "ready": ready,
+            "database": database.err().unwrap_or_else(|| String::from("ok")),
+            "bot": bot.as_str(),
+        }),
+    )
+}
+
+/// curl http://127.0.0.1:8000/metrics
+///
+/// Prometheus metrics, see `metrics::render`. The directory gauges are left out while the database is unreachable.
+#[get("/metrics")]
+fn metrics_text() -> Content<String> {
+    log::debug!("metrics_text() entering...");
+    let health: Health = supervisor::health();
+    let mut gauges: Vec<Gauge> = vec![Gauge {
+        name: "slackrypt_bot_connected",
+        help: "Whether the Slack bot is connected.",
+        value: i64::from(health.state == BotState::Connected),
+    }];
+    if let (Ok(users), Ok(serial)) = (db::get_users(), db::get_directory_serial()) {
+        gauges.push(Gauge {
+            name: "slackrypt_users",
+            help: "Workspace members in the directory.",
+            value: users.len() as i64,
+        });
+        gauges.push(Gauge {
+            name: "slackrypt_registered_keys",
+            help: "Users with a current key.",
+            value: users.iter().filter(|u| u.fingerprint.is_some()).count() as i64,
+        });
+        gauges.push(Gauge {
+            name: "slackrypt_directory_serial",
+            help: "The directory revision.",
+            value: serial,
+        });
+    }
+    Content(
+        ContentType::with_params("text", "plain", ("version", "0.0.4")),
+        metrics::render(&gauges),
+    )
+}
+
+/// Logs `e` and answers `503 Service Unavailable`, for `map_err` on store calls. Clients retry their next poll.
+fn unavailable(e: StoreError) -> Status {
+    log::error!("Key directory unavailable: {}", e);
+    Status::ServiceUnavailable
+}
+
+/// curl -H "Content-Type: application/json" -H "Authorization: Bearer slackrypt_..." http://127.0.0.1:8000/pubkey/users
+///
+/// Kept for older clients, new ones should use `/api/v1/users`. Like every route listing the workspace members
+/// it needs an API token from the bot, see `tokens`.
 #[get("/pubkey/users")]
-fn pubkey_users() -> Option<JsonValue> {
+fn pubkey_users(
+    _limit: DirectoryLimit,
+    _user: ApiUser,
+    if_none_match: IfNoneMatch,
+) -> Result<Tagged<Option<JsonValue>>, Status> {
     log::debug!("pubkey_users() entering...");
-    let users: Vec<String> = db::get_users_all().unwrap();
-    if users.is_empty() {
-        None
-    } else {
-        Some(json!(users))
+    let serial: i64 = db::get_directory_serial().map_err(unavailable)?;
+    etag::tagged(&if_none_match, serial, || {
+        let users: Vec<String> = db::get_users_all().map_err(unavailable)?;
+        if users.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(json!(users)))
+        }
+    })
+}
+
+fn key_json(key: &db::Key) -> JsonValue {
+    json!({
+        "user_id": key.user_id,
+        "pubkey": key.pubkey,
+        "fingerprint": key.fingerprint,
+        "created_at": key.created_at,
+        "revoked_at": key.revoked_at,
+        "revocation_reason": key.revocation_reason,
+    })
+}
+
+/// curl -H "Content-Type: application/json" -H "Authorization: Bearer slackrypt_..." http://127.0.0.1:8000/pubkey/users/U1234ABC
+///
+/// The user's current, unrevoked key.
+#[get("/pubkey/users/<user_id>")]
+fn pubkey_user(
+    _limit: HttpLimit,
+    _user: ApiUser,
+    user_id: String,
+) -> Result<Option<JsonValue>, Status> {
+    log::debug!("pubkey_user() entering...");
+    let key: Option<db::Key> = db::select_current_key(&user_id).map_err(unavailable)?;
+    Ok(key.map(|key| key_json(&key)))
+}
+
+/// curl -H "Content-Type: application/json" -H "Authorization: Bearer slackrypt_..." http://127.0.0.1:8000/pubkey/users/U1234ABC/history
+///
+/// Every key the user ever registered, oldest first, including when and why it was revoked.
+#[get("/pubkey/users/<user_id>/history")]
+fn pubkey_user_history(
+    _limit: HttpLimit,
+    _user: ApiUser,
+    user_id: String,
+) -> Result<JsonValue, Status> {
+    log::debug!("pubkey_user_history() entering...");
+    let keys: Vec<JsonValue> = db::select_key_history(&user_id)
+        .map_err(unavailable)?
+        .iter()
+        .map(key_json)
+        .collect();
+    Ok(json!(keys))
+}
+
+/// curl -H "Content-Type: application/json" -H "Authorization: Bearer slackrypt_..." http://127.0.0.1:8000/pubkey/fingerprints/ab:cd:...
+///
+/// Whether a key with this fingerprint was ever valid and for which period, e.g. to check who signed an old message.
+#[get("/pubkey/fingerprints/<fingerprint>")]
+fn pubkey_fingerprint(
+    _limit: HttpLimit,
+    _user: ApiUser,
+    fingerprint: String,
+) -> Result<JsonValue, Status> {
+    log::debug!("pubkey_fingerprint() entering...");
+    Ok(
+        match db::select_key_by_fingerprint(&fingerprint).map_err(unavailable)? {
+            Some(key) => json!({
+                "fingerprint": fingerprint,
+                "ever_valid": true,
+                "key": key_json(&key),
+            }),
+            None => json!({
+                "fingerprint": fingerprint,
+                "ever_valid": false,
+            }),
+        },
+    )
+}
+
+/// `payload` is serialized as a string and `signature` is the server's signature over exactly those bytes.
+fn signed(payload: JsonValue) -> JsonValue {
+    let payload: String = payload.to_string();
+    let signature: String = crypto::sign(payload.as_bytes());
+    json!({
+        "payload": payload,
+        "signature": signature,
+    })
+}
+
+/// curl -H "Content-Type: application/json" -H "Authorization: Bearer slackrypt_..." http://127.0.0.1:8000/pubkey/directory
+///
+/// The same users as `/pubkey/users`, wrapped in a signed snapshot with the directory serial and a timestamp.
+/// The ETag is the serial, so `If-None-Match` answers 304 until the directory changes.
+#[get("/pubkey/directory")]
+fn pubkey_directory(
+    _limit: DirectoryLimit,
+    _user: ApiUser,
+    if_none_match: IfNoneMatch,
+) -> Result<Tagged<JsonValue>, Status> {
+    log::debug!("pubkey_directory() entering...");
+    let serial: i64 = db::get_directory_serial().map_err(unavailable)?;
+    etag::tagged(&if_none_match, serial, || {
+        let users: Vec<String> = db::get_users_all().map_err(unavailable)?;
+        Ok(signed(json!({
+            "serial": serial,
+            "timestamp": util::unix_timestamp(),
+            "users": users,
+        })))
+    })
+}
+
+/// curl -H "Content-Type: application/json" -H "Authorization: Bearer slackrypt_..." "http://127.0.0.1:8000/changes?since=42"
+///
+/// A signed delta of the users changed after serial `since`, in the `/pubkey/directory` line format.
+/// A client that applies it to its copy of serial `since` is at `serial`. The serial is read first,
+/// so a change committed in between is sent again next time rather than skipped.
+#[get("/changes?<since>")]
+fn changes(
+    _limit: HttpLimit,
+    _user: ApiUser,
+    since: i64,
+    if_none_match: IfNoneMatch,
+) -> Result<Tagged<JsonValue>, Status> {
+    log::debug!("changes() entering...");
+    let serial: i64 = db::get_directory_serial().map_err(unavailable)?;
+    etag::tagged(&if_none_match, serial, || {
+        let users: Vec<String> = db::get_changes(since)
+            .map_err(unavailable)?
+            .into_iter()
+            .map(db::to_csv)
+            .collect();
+        Ok(signed(json!({
+            "since": since,
+            "serial": serial,
+            "timestamp": util::unix_timestamp(),
+            "users": users,
+        })))
+    })
+}
+
+/// curl -H "Content-Type: application/json" http://127.0.0.1:8000/log/sth
+///
+/// Signed tree head of the key transparency log.
+#[get("/log/sth")]
+fn log_sth(_limit: HttpLimit) -> Result<JsonValue, Status> {
+    log::debug!("log_sth() entering...");
+    let leaves: Vec<merkle::Hash> = db::get_log_leaf_hashes().map_err(unavailable)?;
+    Ok(signed(json!({
+        "tree_size": leaves.len(),
+        "root_hash": util::to_base64_str(&merkle::root(&leaves)),
+        "timestamp": util::unix_timestamp(),
+    })))
+}
+
+/// curl -H "Content-Type: application/json" -H "Authorization: Bearer slackrypt_..." http://127.0.0.1:8000/log/entries/U1234ABC
+#[get("/log/entries/<user_id>")]
+fn log_entries(_limit: HttpLimit, _user: ApiUser, user_id: String) -> Result<JsonValue, Status> {
+    log::debug!("log_entries() entering...");
+    let entries: Vec<JsonValue> = db::get_log_entries(&user_id)
+        .map_err(unavailable)?
+        .into_iter()
+        .map(|e| {
+            json!({
+                "leaf_index": e.leaf_index,
+                "pubkey": e.pubkey,
+                "created_at": e.created_at,
+            })
+        })
+        .collect();
+    Ok(json!(entries))
+}
+
+/// curl -H "Content-Type: application/json" "http://127.0.0.1:8000/log/proof/inclusion?leaf_index=0&tree_size=1"
+#[get("/log/proof/inclusion?<leaf_index>&<tree_size>")]
+fn log_inclusion_proof(
+    _limit: HttpLimit,
+    leaf_index: usize,
+    tree_size: usize,
+) -> Result<Option<JsonValue>, Status> {
+    log::debug!("log_inclusion_proof() entering...");
+    let leaves: Vec<merkle::Hash> = db::get_log_leaf_hashes().map_err(unavailable)?;
+    if leaf_index >= tree_size || tree_size > leaves.len() {
+        return Ok(None);
     }
+    let audit_path: Vec<String> = merkle::inclusion_proof(leaf_index, &leaves[..tree_size])
+        .iter()
+        .map(|h| util::to_base64_str(h))
+        .collect();
+    Ok(Some(json!({
+        "leaf_index": leaf_index,
+        "tree_size": tree_size,
+        "audit_path": audit_path,
+    })))
+}
+
+/// curl -H "Content-Type: application/json" "http://127.0.0.1:8000/log/proof/consistency?first=1&second=2"
+#[get("/log/proof/consistency?<first>&<second>")]
+fn log_consistency_proof(
+    _limit: HttpLimit,
+    first: usize,
+    second: usize,
+) -> Result<Option<JsonValue>, Status> {
+    log::debug!("log_consistency_proof() entering...");
+    let leaves: Vec<merkle::Hash> = db::get_log_leaf_hashes().map_err(unavailable)?;
+    if first > second || second > leaves.len() {
+        return Ok(None);
+    }
+    let consistency: Vec<String> = merkle::consistency_proof(first, &leaves[..second])
+        .iter()
+        .map(|h| util::to_base64_str(h))
+        .collect();
+    Ok(Some(json!({
+        "first": first,
+        "second": second,
+        "consistency": consistency,
+    })))
+}
+
+/// curl -X POST -H "X-Slack-Request-Timestamp: 1531420618" -H "X-Slack-Signature: v0=a2114d57..." -d @event.json http://127.0.0.1:8000/slack/events
+///
+/// The Events API request URL of the Slack app. Only requests signed with `SLACK_SIGNING_SECRET` are accepted,
+/// messages are answered in the background since Slack retries events not acknowledged within 3 seconds.
+#[post("/slack/events", data = "<data>")]
+fn slack_events_callback(request: SlackRequest, data: Data) -> Result<String, Status> {
+    log::debug!("slack_events_callback() entering...");
+    let body: String = request.verified_body(data)?;
+    match slack_events::parse_callback(&body) {
+        Ok(Callback::UrlVerification(challenge)) => Ok(challenge),
+        Ok(Callback::Message(event_id, msg)) => {
+            slack_events::dispatch(&event_id, msg)?;
+            Ok(String::new())
+        }
+        Ok(Callback::MemberChanged(member)) => {
+            let bot = slack_events::bot().ok_or(Status::ServiceUnavailable)?;
+            bot.sync_member(member);
+            Ok(String::new())
+        }
+        Ok(Callback::Ignored) => Ok(String::new()),
+        Err(e) => {
+            log::warn!("Could not parse Slack event: {}", e);
+            Err(Status::BadRequest)
+        }
+    }
+}
+
+/// curl -X POST -H "X-Slack-Request-Timestamp: 1531420618" -H "X-Slack-Signature: v0=a2114d57..." -d "command=%2Fslackrypt&text=status&user_id=U1234ABC&channel_id=C0LAN2Q65" http://127.0.0.1:8000/slack/commands
+///
+/// The request URL of the `/slackrypt` slash command. It is verified like `/slack/events` and answered by the same
+/// commands as mentions of the bot, visible only to the user who ran it.
+#[post("/slack/commands", data = "<data>")]
+fn slack_command(request: SlackRequest, data: Data) -> Result<JsonValue, Status> {
+    log::debug!("slack_command() entering...");
+    let body: String = request.verified_body(data)?;
+    let command: SlashCommand = slack_events::parse_command(&body).map_err(|e| {
+        log::warn!("Could not parse slash command: {}", e);
+        Status::BadRequest
+    })?;
+    let bot = slack_events::bot().ok_or(Status::ServiceUnavailable)?;
+    Ok(json!({
+        "response_type": "ephemeral",
+        "text": bot.command(&command.user_id, &command.text),
+    }))
 }
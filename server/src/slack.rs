@@ -2,36 +2,41 @@ use log::{debug, error, info};
 use slack::api::rtm::StartResponse;
 use slack::api::{Channel, Message, MessageStandard, User};
 use slack::{Event, RtmClient};
-use std::collections::HashMap;
 use std::vec::Vec;
 
+use crate::bot::{Bot, Incoming};
+use crate::config;
 use crate::db;
-use crate::util;
+use crate::db::Member;
+use crate::slack_api::SlackApi;
+use crate::supervisor;
 
 struct SlackHandler {
     api_key: String,
-    server_base_url: String,
     direct_msg_prefix: char,
-    user_id: String,
     real_name: String,
-    reply_pattern: String,
-    users_cache: HashMap<String, String>,
+    bot: Bot,
 }
 
 impl SlackHandler {
-    fn should_reply(&self, event_text: &str) -> bool {
-        event_text.starts_with(&self.reply_pattern)
-    }
-
     fn is_direct_msg(&self, channel_id: &str) -> bool {
         channel_id.starts_with(self.direct_msg_prefix)
     }
+}
 
-    fn is_public_key(&self, event_text: &str, channel_id: &str) -> bool {
-        self.is_direct_msg(channel_id)
-            && event_text.starts_with("-----BEGIN PUBLIC KEY-----")
-            && event_text.ends_with("-----END PUBLIC KEY-----")
+/// The member a Slack user is, `None` for bots.
+fn member_from_user(u: &User) -> Option<Member> {
+    if u.is_bot.unwrap_or(false) {
+        return None;
     }
+    Some(Member {
+        deleted: u.deleted.unwrap_or(false),
+        ..Member::new(
+            u.id.as_ref()?,
+            u.name.as_ref()?,
+            u.real_name.as_deref().unwrap_or(""),
+        )
+    })
 }
 
 impl slack::EventHandler for SlackHandler {
@@ -72,55 +77,30 @@ impl slack::EventHandler for SlackHandler {
                 debug!("################################# Event::DesktopNotification");
                 return;
             }
+            Event::TeamJoin { ref user } | Event::UserChange { ref user } => {
+                if let Some(member) = member_from_user(user) {
+                    self.bot.sync_member(member);
+                }
+                return;
+            }
             Event::Goodbye => {
+                // Slack is about to close the connection, `login_and_run` returns and the supervisor reconnects
                 info!("################################# Event::Goodbye");
-                start(self)
+                let _ = cli.sender().shutdown();
+                return;
             }
             _ => debug!("Event not decoded, ignore it."),
         }
 
         // listen for commands
-        if (&event_text == "init" || &event_text == "help") && self.is_direct_msg(&channel_id) {
-            let mut response: String = format!(
-                "Run this in your terminal: `curl -sSf https://{}/init.sh | sh`",
-                &self.server_base_url
-            );
-            response.push_str("\n\nAfter that is done, please paste your public key found at `~/.slackrypt/key.pem.pub`");
-            let _ = cli.sender().send_message(&channel_id, &response);
-        }
-
-        if self.is_public_key(&event_text.trim(), &channel_id) {
-            let user_name: String = match self.users_cache.get(&sender) {
-                Some(name) => name.clone(),
-                None => "FIXME".to_string(),
-            };
-            // FIXME Error when new user is added to the Slack org after this Slackbot connects (i.e. initial load of users).
-            // thread 'main' panicked at 'called `Option::unwrap()` on a `None` value'.
-            // Just check in the cache first, then manually add (i.e. when new user joins Slack workspace after launch).
-            // Problem is Event::Message doesn't have the correct user name and Event::DesktopNotification doesn't have the user id.
-            // This might have to be a PR to upstream slack crate.
-
-            let _ = db::upsert_pubkey(&sender, &user_name, event_text.trim()).unwrap();
-            let response: String =
-                format!("Thank you. If you're curious, your Slack id is {}", &sender);
-            let _ = cli.sender().send_message(&channel_id, &response);
-        }
-
-        if self.should_reply(&event_text) {
-            let args: Vec<&str> = event_text.split(' ').collect();
-            debug!("args are {:?}", args);
-            if args.len() > 1 {
-                //add DM commands here that need action
-                if args[1] == "help" {
-                    let _ = cli
-                        .sender()
-                        .send_message(&channel_id, "DM me with the command 'init' to get started.");
-                } else {
-                    let response: String =
-                        format!("I haven't learned how to execute '{}' yet.", args[1]);
-                    let _ = cli.sender().send_message(&channel_id, &response);
-                }
-            }
+        let incoming: Incoming = Incoming {
+            direct: self.is_direct_msg(&channel_id),
+            sender,
+            channel_id,
+            text: event_text,
+        };
+        if let Some(response) = self.bot.handle(&incoming) {
+            let _ = cli.sender().send_message(&incoming.channel_id, &response);
         }
     }
 
@@ -130,7 +110,7 @@ impl slack::EventHandler for SlackHandler {
 
     fn on_connect(&mut self, cli: &RtmClient) {
         info!("on_connect");
-        let channel_name: String = util::get_env_var("SLACK_CHANNEL_NAME", "general");
+        let channel_name: String = config::get().slack.channel_name.clone();
         let resp: &StartResponse = cli.start_response();
         let users: &Vec<User> = resp.users.as_ref().expect("Could not get users");
         let channels: &Vec<Channel> = resp.channels.as_ref().expect("Could not get channels");
@@ -145,18 +125,11 @@ impl slack::EventHandler for SlackHandler {
             .unwrap();
         let channel_id: String = channel.id.as_ref().unwrap().to_string();
 
-        // find all human users to persist initial info
-        let mut user_info: Vec<(&str, &str, &str)> = Vec::new(); //FIXME Make a struct in db.rs
-        for u in users {
-            if !u.is_bot.unwrap() && !u.deleted.unwrap() {
-                user_info.push((u.id.as_ref().unwrap(), u.name.as_ref().unwrap(), ""));
-                self.users_cache.insert(
-                    u.id.as_ref().unwrap().to_string(),
-                    u.name.as_ref().unwrap().to_string(),
-                );
-            }
+        // find all human users to persist initial info, deactivated ones are marked deleted
+        let members: Vec<Member> = users.iter().filter_map(member_from_user).collect();
+        if let Err(e) = db::upsert_members(&members) {
+            error!("Could not store the workspace members: {}", e);
         }
-        db::insert_pubkeys(&user_info).unwrap();
 
         // find bot user id
         let this_bot_user: &User = users
@@ -172,44 +145,46 @@ impl slack::EventHandler for SlackHandler {
             .unwrap();
         assert!(this_bot_user.is_bot.unwrap());
         assert!(!this_bot_user.deleted.unwrap());
-        self.user_id = this_bot_user.id.as_ref().unwrap().to_string();
-
-        // set the String pattern to look for when responding to @Slackrypt <command>
-        self.reply_pattern = "<@".to_string() + &self.user_id + "> ";
+        self.bot.user_id = this_bot_user.id.as_ref().unwrap().to_string();
 
         // Send connected message to channel
         let connection_msg: String =
             String::from("I'm up! Simply DM me with 'init' to get started.");
         let _ = cli.sender().send_message(&channel_id, &connection_msg);
+
+        let sender = cli.sender().clone();
+        supervisor::on_stop(move || {
+            let _ = sender.shutdown();
+        });
+        supervisor::connected();
     }
 }
 
-pub fn init(server_base_url: &str) {
+/// Runs the bot over the legacy RTM API, for classic Slack apps without a signing secret,
+/// reconnecting until shutdown or `max_reconnects` failures in a row.
+pub fn init(server_base_url: &str, max_reconnects: u32) {
     info!("Initializing Slack RTM client...");
-    let api_key: String = util::get_env_var("BOTUSER_AUTH_ACCESS_TOKEN", "");
-    let botuser_name: String = util::get_env_var("BOTUSER_REAL_NAME", "Slackrypt");
-    let hash_map = HashMap::new();
+    let api_key: String = config::get().slack.bot_token.clone();
+    let botuser_name: String = config::get().slack.bot_real_name.clone();
     let mut slack_handler = SlackHandler {
+        bot: Bot::new(server_base_url, "unknown", SlackApi::new(&api_key)),
         api_key,
-        server_base_url: server_base_url.to_string(),
         direct_msg_prefix: 'D',
-        user_id: "unknown".to_string(),
         real_name: botuser_name,
-        reply_pattern: "unknown".to_string(),
-        users_cache: hash_map,
     };
-    start(&mut slack_handler)
+    supervisor::supervise(max_reconnects, || start(&mut slack_handler))
 }
 
-fn start(slack_handler: &mut SlackHandler) {
+fn start(slack_handler: &mut SlackHandler) -> Result<(), String> {
     info!("Starting Slack RTM client...");
     match RtmClient::login_and_run(&slack_handler.api_key.to_string(), slack_handler) {
         Ok(()) => {
             info!("RTM client login_and_run successfully closed!");
+            Ok(())
         }
         Err(err) => {
             error!("Error when attempting to login and run!");
-            panic!("Err: Could not login and start slack client! {}", err)
+            Err(format!("Could not login and start slack client! {}", err))
         }
     }
 }
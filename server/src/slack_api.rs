@@ -0,0 +1,162 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::vec::Vec;
+
+use reqwest::blocking::Client;
+use serde_json::Value;
+
+use crate::db::Member;
+
+const SLACK_API_URL: &str = "https://slack.com/api";
+
+/// Members requested per `users.list` page, Slack recommends at most 200.
+const USERS_PAGE_LIMIT: &str = "200";
+
+#[derive(Debug)]
+pub struct SlackApiError {
+    reason: String,
+}
+
+impl SlackApiError {
+    pub fn new(reason: &str) -> SlackApiError {
+        SlackApiError {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl Error for SlackApiError {}
+
+impl Display for SlackApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Slack Web API error: {}", self.reason)
+    }
+}
+
+impl From<reqwest::Error> for SlackApiError {
+    fn from(e: reqwest::Error) -> Self {
+        SlackApiError::new(&e.to_string())
+    }
+}
+
+/// The few Slack Web API methods the bot calls, authenticated with the bot token.
+pub struct SlackApi {
+    token: String,
+    client: Client,
+}
+
+impl SlackApi {
+    pub fn new(token: &str) -> SlackApi {
+        SlackApi {
+            token: token.to_string(),
+            client: Client::new(),
+        }
+    }
+
+    /// Calls `method` with form encoded `params`, failing unless Slack answers `"ok": true`.
+    fn call(&self, method: &str, params: &[(&str, &str)]) -> Result<Value, SlackApiError> {
+        log::debug!("Calling Slack Web API method {}", method);
+        let resp: Value = self
+            .client
+            .post(format!("{}/{}", SLACK_API_URL, method))
+            .bearer_auth(&self.token)
+            .form(params)
+            .send()?
+            .error_for_status()?
+            .json()?;
+        if resp["ok"].as_bool() == Some(true) {
+            Ok(resp)
+        } else {
+            Err(SlackApiError::new(&format!(
+                "{} failed: {}",
+                method,
+                resp["error"].as_str().unwrap_or("unknown error")
+            )))
+        }
+    }
+
+    /// The bot's own user id.
+    pub fn auth_test(&self) -> Result<String, SlackApiError> {
+        let resp: Value = self.call("auth.test", &[])?;
+        match resp["user_id"].as_str() {
+            Some(user_id) => Ok(user_id.to_string()),
+            None => Err(SlackApiError::new("auth.test returned no user_id")),
+        }
+    }
+
+    /// Posts `text` to a channel (id or name) or a DM.
+    pub fn post_message(&self, channel: &str, text: &str) -> Result<(), SlackApiError> {
+        self.call("chat.postMessage", &[("channel", channel), ("text", text)])?;
+        Ok(())
+    }
+
+    /// A member looked up by id, `None` for bots.
+    pub fn users_info(&self, user_id: &str) -> Result<Option<Member>, SlackApiError> {
+        let resp: Value = self.call("users.info", &[("user", user_id)])?;
+        Ok(member_from_json(&resp["user"]))
+    }
+
+    /// Every human member of the workspace including deactivated ones, following `users.list` pagination.
+    pub fn users_list(&self) -> Result<Vec<Member>, SlackApiError> {
+        let mut members: Vec<Member> = Vec::new();
+        let mut cursor: String = String::new();
+        loop {
+            let resp: Value = self.call(
+                "users.list",
+                &[("limit", USERS_PAGE_LIMIT), ("cursor", &cursor)],
+            )?;
+            if let Some(users) = resp["members"].as_array() {
+                members.extend(users.iter().filter_map(member_from_json));
+            }
+            cursor = resp["response_metadata"]["next_cursor"]
+                .as_str()
+                .unwrap_or("")
+                .to_string();
+            if cursor.is_empty() {
+                return Ok(members);
+            }
+        }
+    }
+}
+
+/// The member described by a Slack user object, `None` for bots.
+pub fn member_from_json(user: &Value) -> Option<Member> {
+    if user["is_bot"].as_bool().unwrap_or(false) {
+        return None;
+    }
+    Some(Member {
+        deleted: user["deleted"].as_bool().unwrap_or(false),
+        ..Member::new(
+            user["id"].as_str()?,
+            user["name"].as_str()?,
+            user["real_name"].as_str().unwrap_or(""),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_member_from_json() {
+        let resp: Value =
+            serde_json::from_str(include_str!("../fixtures/slack/users_list.json")).unwrap();
+        let members: Vec<Member> = resp["members"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(member_from_json)
+            .collect();
+        assert_eq!(
+            members,
+            vec![
+                Member::new("W012A3CDE", "spengler", "Egon Spengler"),
+                Member {
+                    deleted: true,
+                    ..Member::new("W07QCRPA4", "glinda", "")
+                }
+            ]
+        );
+    }
+}
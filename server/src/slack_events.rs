@@ -0,0 +1,546 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::Display;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use hmac::{Hmac, Mac};
+use rocket::data::Data;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::bot::{Bot, Incoming};
+use crate::config::{self, SlackConfig};
+use crate::db;
+use crate::db::Member;
+use crate::slack_api::{self, SlackApi, SlackApiError};
+use crate::store::StoreError;
+use crate::supervisor;
+use crate::util;
+
+/// Requests signed longer ago than this are rejected, so a captured request cannot be replayed.
+pub const SIGNATURE_MAX_AGE_SECS: i64 = 300;
+
+/// Slack's payloads are small, anything larger is not from Slack.
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+/// Event ids remembered to skip Slack's retries of events already dispatched.
+const RECENT_EVENTS: usize = 1000;
+
+/// Threads answering dispatched messages, and how many messages may wait for one. Further messages get
+/// `503 Service Unavailable` and Slack retries them later.
+const WORKERS: usize = 4;
+const QUEUED_MESSAGES: usize = 100;
+
+/// The bot answering events, set once the initial sync with Slack is done.
+static BOT: OnceLock<Bot> = OnceLock::new();
+
+/// Messages waiting for a worker, the workers start with the first message.
+static QUEUE: OnceLock<SyncSender<(&'static Bot, Incoming)>> = OnceLock::new();
+
+/// The ids of the last `RECENT_EVENTS` events dispatched, oldest first.
+static DISPATCHED: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// The app's signing secret (`slack.signing_secret`), empty when the bot runs over the legacy RTM API.
+pub fn signing_secret() -> String {
+    config::get().slack.signing_secret.clone()
+}
+
+/// Checks `signature` (`v0=<hex HMAC-SHA256 of "v0:<timestamp>:<body>">`) and that `timestamp` is recent.
+pub fn verify_signature(
+    secret: &str,
+    timestamp: &str,
+    body: &str,
+    signature: &str,
+    now: i64,
+) -> Result<(), String> {
+    if secret.is_empty() {
+        return Err(String::from("SLACK_SIGNING_SECRET is not set"));
+    }
+    let signed_at: i64 = timestamp
+        .parse()
+        .map_err(|_| format!("Invalid request timestamp '{}'", timestamp))?;
+    if (now - signed_at).abs() > SIGNATURE_MAX_AGE_SECS {
+        return Err(format!("Request timestamp {} is too old", signed_at));
+    }
+    let expected: Vec<u8> = signature
+        .strip_prefix("v0=")
+        .and_then(|hex| hex::decode(hex).ok())
+        .ok_or_else(|| String::from("Malformed request signature"))?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("v0:{}:{}", timestamp, body).as_bytes());
+    mac.verify_slice(&expected)
+        .map_err(|_| String::from("Request signature does not match"))
+}
+
+/// The signature headers of a request from Slack.
+pub struct SlackRequest {
+    timestamp: String,
+    signature: String,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for SlackRequest {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let headers = request.headers();
+        match (
+            headers.get_one("X-Slack-Request-Timestamp"),
+            headers.get_one("X-Slack-Signature"),
+        ) {
+            (Some(timestamp), Some(signature)) => Outcome::Success(SlackRequest {
+                timestamp: timestamp.to_string(),
+                signature: signature.to_string(),
+            }),
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+impl SlackRequest {
+    /// Reads the body, `401 Unauthorized` unless it was signed with our signing secret.
+    pub fn verified_body(&self, data: Data) -> Result<String, Status> {
+        let mut body: String = String::new();
+        data.open()
+            .take(MAX_BODY_BYTES)
+            .read_to_string(&mut body)
+            .map_err(|_| Status::BadRequest)?;
+        match verify_signature(
+            &signing_secret(),
+            &self.timestamp,
+            &body,
+            &self.signature,
+            util::unix_timestamp(),
+        ) {
+            Ok(()) => Ok(body),
+            Err(e) => {
+                log::warn!("Rejecting Slack request: {}", e);
+                Err(Status::Unauthorized)
+            }
+        }
+    }
+}
+
+/// What an Events API request asks of the bot.
+#[derive(Debug, PartialEq)]
+pub enum Callback {
+    /// Slack checking the request URL, answered with the challenge.
+    UrlVerification(String),
+    /// A DM (`message.im`) or an `app_mention`, with the `event_id` Slack keeps across retries.
+    Message(String, Incoming),
+    /// A member who joined (`team_join`), changed their profile or was deactivated (`user_change`).
+    MemberChanged(Member),
+    Ignored,
+}
+
+pub fn parse_callback(body: &str) -> Result<Callback, String> {
+    let payload: Value = serde_json::from_str(body).map_err(|e| e.to_string())?;
+    match payload["type"].as_str() {
+        Some("url_verification") => match payload["challenge"].as_str() {
+            Some(challenge) => Ok(Callback::UrlVerification(challenge.to_string())),
+            None => Err(String::from("url_verification without a challenge")),
+        },
+        Some("event_callback") => Ok(parse_event(
+            payload["event_id"].as_str().unwrap_or(""),
+            &payload["event"],
+        )),
+        _ => Ok(Callback::Ignored),
+    }
+}
+
+fn parse_event(event_id: &str, event: &Value) -> Callback {
+    let direct: bool = match (event["type"].as_str(), event["channel_type"].as_str()) {
+        (Some("message"), Some("im")) => true,
+        (Some("app_mention"), _) => false,
+        (Some("team_join"), _) | (Some("user_change"), _) => {
+            return match slack_api::member_from_json(&event["user"]) {
+                Some(member) => Callback::MemberChanged(member),
+                None => Callback::Ignored,
+            }
+        }
+        _ => return Callback::Ignored,
+    };
+    // edits, joins and the bot's own replies arrive as messages too
+    if !event["subtype"].is_null() || !event["bot_id"].is_null() {
+        return Callback::Ignored;
+    }
+    match (
+        event["user"].as_str(),
+        event["channel"].as_str(),
+        event["text"].as_str(),
+    ) {
+        (Some(sender), Some(channel_id), Some(text)) => Callback::Message(
+            event_id.to_string(),
+            Incoming {
+                sender: sender.to_string(),
+                channel_id: channel_id.to_string(),
+                text: text.to_string(),
+                direct,
+            },
+        ),
+        _ => Callback::Ignored,
+    }
+}
+
+/// A slash command invocation, e.g. `/slackrypt key @jeff`.
+#[derive(Debug, PartialEq)]
+pub struct SlashCommand {
+    pub command: String,
+    pub user_id: String,
+    pub channel_id: String,
+    /// Everything after the command name.
+    pub text: String,
+}
+
+/// Parses the form encoded body Slack posts for a slash command.
+pub fn parse_command(body: &str) -> Result<SlashCommand, String> {
+    let mut command: SlashCommand = SlashCommand {
+        command: String::new(),
+        user_id: String::new(),
+        channel_id: String::new(),
+        text: String::new(),
+    };
+    for (key, value) in form_urlencoded::parse(body.trim_end().as_bytes()) {
+        match key.as_ref() {
+            "command" => command.command = value.into_owned(),
+            "user_id" => command.user_id = value.into_owned(),
+            "channel_id" => command.channel_id = value.into_owned(),
+            "text" => command.text = value.into_owned(),
+            _ => {}
+        }
+    }
+    if command.command.is_empty() || command.user_id.is_empty() {
+        return Err(String::from("Slash command without command or user_id"));
+    }
+    Ok(command)
+}
+
+/// The bot answering events and commands, `None` until `init` is done.
+pub fn bot() -> Option<&'static Bot> {
+    BOT.get()
+}
+
+/// Whether `event_id` was not dispatched yet, and remembers it. Events without an id are always dispatched.
+fn first_delivery(event_id: &str) -> bool {
+    if event_id.is_empty() {
+        return true;
+    }
+    let mut dispatched = DISPATCHED.lock().unwrap();
+    if dispatched.iter().any(|id| id == event_id) {
+        return false;
+    }
+    if dispatched.len() >= RECENT_EVENTS {
+        dispatched.pop_front();
+    }
+    dispatched.push_back(event_id.to_string());
+    true
+}
+
+/// Forgets `event_id` was dispatched, so Slack's retry of it is answered.
+fn forget_delivery(event_id: &str) {
+    DISPATCHED.lock().unwrap().retain(|id| id != event_id);
+}
+
+fn queue() -> &'static SyncSender<(&'static Bot, Incoming)> {
+    QUEUE.get_or_init(|| {
+        let (tx, rx) = mpsc::sync_channel::<(&'static Bot, Incoming)>(QUEUED_MESSAGES);
+        let rx: Arc<Mutex<Receiver<(&'static Bot, Incoming)>>> = Arc::new(Mutex::new(rx));
+        for _ in 0..WORKERS {
+            let rx = Arc::clone(&rx);
+            thread::spawn(move || loop {
+                let (bot, msg) = match rx.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                };
+                // a message that panics the bot must not take the worker with it
+                if panic::catch_unwind(AssertUnwindSafe(|| answer(bot, &msg))).is_err() {
+                    log::error!("Could not answer {}, the bot panicked", msg.sender);
+                }
+            });
+        }
+        tx
+    })
+}
+
+fn answer(bot: &Bot, msg: &Incoming) {
+    if let Some(response) = bot.handle(msg) {
+        if let Err(e) = bot.api.post_message(&msg.channel_id, &response) {
+            log::error!("Could not reply to {}: {}", msg.sender, e);
+        }
+    }
+}
+
+/// Queues `msg` for the workers, Slack expects the HTTP response within 3 seconds. Slack resends an event it
+/// got no timely `2xx` for, a retry of an event already dispatched is skipped. `503 Service Unavailable` while the
+/// bot is not ready or `QUEUED_MESSAGES` wait already, so Slack retries the event later instead of it being lost.
+pub fn dispatch(event_id: &str, msg: Incoming) -> Result<(), Status> {
+    let bot: &'static Bot = match BOT.get() {
+        Some(bot) => bot,
+        None => {
+            log::warn!(
+                "Slack bot is not ready yet, asking Slack to retry the message from {}",
+                msg.sender
+            );
+            return Err(Status::ServiceUnavailable);
+        }
+    };
+    if !first_delivery(event_id) {
+        log::debug!("Skipping retry of event {}", event_id);
+        return Ok(());
+    }
+    match queue().try_send((bot, msg)) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full((_, msg))) | Err(TrySendError::Disconnected((_, msg))) => {
+            log::warn!(
+                "{} messages are waiting already, asking Slack to retry the message from {}",
+                QUEUED_MESSAGES,
+                msg.sender
+            );
+            forget_delivery(event_id);
+            Err(Status::ServiceUnavailable)
+        }
+    }
+}
+
+/// Runs the bot over the Events API until shutdown. Slack pushes events to us, so there is no connection to keep
+/// up, only the initial sync is retried until it succeeds or `max_reconnects` attempts in a row failed.
+pub fn init(server_base_url: &str, max_reconnects: u32) {
+    supervisor::supervise(max_reconnects, || {
+        connect(server_base_url).map_err(|e| e.to_string())?;
+        supervisor::connected();
+        supervisor::wait_for_shutdown();
+        Ok(())
+    })
+}
+
+/// Why the workspace members could not be synced: Slack did not list them or they could not be stored.
+#[derive(Debug)]
+pub enum SyncError {
+    Slack(SlackApiError),
+    Store(StoreError),
+}
+
+impl Error for SyncError {}
+
+impl Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::Slack(e) => e.fmt(f),
+            SyncError::Store(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<SlackApiError> for SyncError {
+    fn from(e: SlackApiError) -> Self {
+        SyncError::Slack(e)
+    }
+}
+
+impl From<StoreError> for SyncError {
+    fn from(e: StoreError) -> Self {
+        SyncError::Store(e)
+    }
+}
+
+/// Stores every member of the workspace, returns how many there are.
+pub fn sync_members(api: &SlackApi) -> Result<usize, SyncError> {
+    let members: Vec<Member> = api.users_list()?;
+    db::upsert_members(&members)?;
+    Ok(members.len())
+}
+
+/// Syncs the workspace members, after which events are answered, and announces the bot.
+fn connect(server_base_url: &str) -> Result<(), SyncError> {
+    log::info!("Initializing Slack Events API bot...");
+    let slack: &SlackConfig = &config::get().slack;
+    let api: SlackApi = SlackApi::new(&slack.bot_token);
+    let user_id: String = api.auth_test()?;
+    sync_members(&api)?;
+
+    let bot: &Bot = BOT.get_or_init(|| Bot::new(server_base_url, &user_id, api));
+    // the bot answers events from here on, the announcement is only a courtesy
+    if let Err(e) = bot.api.post_message(
+        &slack.channel_name,
+        "I'm up! Simply DM me with 'init' to get started.",
+    ) {
+        log::warn!(
+            "Could not announce the bot in {}: {}",
+            slack.channel_name,
+            e
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+
+    fn sign(timestamp: &str, body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(format!("v0:{}:{}", timestamp, body).as_bytes());
+        format!("v0={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        // the example from https://api.slack.com/authentication/verifying-requests-from-slack
+        let body: &str = include_str!("../fixtures/slack/slash_command.txt").trim_end();
+        let signature: &str = "v0=a2114d57b48eac39b9ad189dd8316235a7b4a8d21a10bd27519666489c69b503";
+        assert_eq!(
+            verify_signature(SECRET, "1531420618", body, signature, 1531420618 + 60),
+            Ok(())
+        );
+        assert!(verify_signature(SECRET, "1531420618", body, signature, 1531420618 + 301).is_err());
+        assert!(verify_signature(SECRET, "1531420618", body, signature, 1531420618 - 301).is_err());
+        assert!(verify_signature(SECRET, "1531420619", body, signature, 1531420618).is_err());
+        assert!(verify_signature(
+            SECRET,
+            "1531420618",
+            &(body.to_string() + "x"),
+            signature,
+            1531420618
+        )
+        .is_err());
+        assert!(verify_signature("other", "1531420618", body, signature, 1531420618).is_err());
+        assert!(verify_signature("", "1531420618", body, signature, 1531420618).is_err());
+        assert!(verify_signature(SECRET, "1531420618", body, "a2114d57", 1531420618).is_err());
+        assert!(verify_signature(SECRET, "yesterday", body, signature, 1531420618).is_err());
+    }
+
+    #[test]
+    fn test_first_delivery() {
+        let msg = Incoming {
+            sender: "U1234ABC".to_string(),
+            channel_id: "D024BE91L".to_string(),
+            text: "init".to_string(),
+            direct: true,
+        };
+        // not remembered while the bot is not ready, so Slack's retry is answered
+        assert_eq!(dispatch("EvRETRY001", msg), Err(Status::ServiceUnavailable));
+        assert!(first_delivery("EvRETRY001"));
+        assert!(!first_delivery("EvRETRY001"));
+        forget_delivery("EvRETRY001");
+        assert!(first_delivery("EvRETRY001"));
+        assert!(!first_delivery("EvRETRY001"));
+        assert!(first_delivery(""));
+        assert!(first_delivery(""));
+
+        for i in 0..RECENT_EVENTS {
+            first_delivery(&format!("EvFILL{}", i));
+        }
+        assert!(first_delivery("EvRETRY001"));
+    }
+
+    #[test]
+    fn test_parse_callback() {
+        let body: &str = include_str!("../fixtures/slack/url_verification.json");
+        assert_eq!(
+            parse_callback(body),
+            Ok(Callback::UrlVerification(
+                "3eZbrw1aBm2rZgRNFdxV2595E9CY3gmdALWMmHkvFXO7tYXAYM8P".to_string()
+            ))
+        );
+
+        let body: &str = include_str!("../fixtures/slack/message_im.json");
+        assert_eq!(
+            verify_signature(
+                SECRET,
+                "1700000000",
+                body,
+                &sign("1700000000", body),
+                1700000000
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            parse_callback(body),
+            Ok(Callback::Message(
+                "Ev0LAN670R".to_string(),
+                Incoming {
+                    sender: "U1234ABC".to_string(),
+                    channel_id: "D024BE91L".to_string(),
+                    text: "init".to_string(),
+                    direct: true,
+                }
+            ))
+        );
+
+        assert_eq!(
+            parse_callback(include_str!("../fixtures/slack/app_mention.json")),
+            Ok(Callback::Message(
+                "Ev0LAN670R".to_string(),
+                Incoming {
+                    sender: "U1234ABC".to_string(),
+                    channel_id: "C0LAN2Q65".to_string(),
+                    text: "<@U0LAN0Z89> help".to_string(),
+                    direct: false,
+                }
+            ))
+        );
+
+        assert_eq!(
+            parse_callback(include_str!("../fixtures/slack/message_im_bot.json")),
+            Ok(Callback::Ignored)
+        );
+        assert_eq!(
+            parse_callback(include_str!("../fixtures/slack/team_join.json")),
+            Ok(Callback::MemberChanged(Member::new(
+                "W012A3CDE",
+                "spengler",
+                "Egon Spengler"
+            )))
+        );
+        assert_eq!(
+            parse_callback(include_str!(
+                "../fixtures/slack/user_change_deactivated.json"
+            )),
+            Ok(Callback::MemberChanged(Member {
+                deleted: true,
+                ..Member::new("W012A3CDE", "egon", "Egon Spengler")
+            }))
+        );
+        assert!(parse_callback("not json").is_err());
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let body: &str = include_str!("../fixtures/slack/slackrypt_command.txt");
+        assert_eq!(
+            verify_signature(
+                SECRET,
+                "1700000000",
+                body,
+                &sign("1700000000", body),
+                1700000000
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            parse_command(body),
+            Ok(SlashCommand {
+                command: "/slackrypt".to_string(),
+                user_id: "U1234ABC".to_string(),
+                channel_id: "C0LAN2Q65".to_string(),
+                text: "key <@U2CERLKJA|roadrunner>".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_command(include_str!("../fixtures/slack/slash_command.txt"))
+                .unwrap()
+                .text,
+            ""
+        );
+        assert!(parse_command("text=help").is_err());
+    }
+}
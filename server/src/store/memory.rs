@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::crypto;
+use crate::merkle;
+use crate::store::{
+    audit_hash, log_leaf_hash, ApiToken, AuditEvent, AuditQuery, Key, KeyDirectory, LogEntry,
+    Member, PendingKey, StoreError, StoreResult, User, AUDIT_GENESIS_HASH,
+};
+use crate::util;
+
+#[derive(Default)]
+struct State {
+    users: HashMap<String, Member>,
+    pubkeys: HashMap<String, String>,
+    revisions: HashMap<String, i64>,
+    keys: Vec<Key>,
+    log: Vec<(String, LogEntry, merkle::Hash)>,
+    pending_keys: HashMap<String, PendingKey>,
+    serial: i64,
+    audit: Vec<AuditEvent>,
+    api_tokens: HashMap<String, ApiToken>,
+}
+
+impl State {
+    fn user(&self, member: &Member) -> User {
+        let current: Option<&Key> = self
+            .keys
+            .iter()
+            .find(|k| k.user_id == member.user_id && k.revoked_at.is_none());
+        User {
+            user_id: member.user_id.clone(),
+            name: member.name.clone(),
+            real_name: member.real_name.clone(),
+            deleted: member.deleted,
+            pubkey: self
+                .pubkeys
+                .get(&member.user_id)
+                .cloned()
+                .unwrap_or_default(),
+            fingerprint: current.and_then(|k| k.fingerprint.clone()),
+            updated_at: current.map(|k| k.created_at),
+            revision: self
+                .revisions
+                .get(&member.user_id)
+                .copied()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// A volatile backend for unit tests and throwaway local runs, everything is lost on restart.
+#[derive(Default)]
+pub struct MemoryDirectory {
+    state: Mutex<State>,
+}
+
+impl MemoryDirectory {
+    fn state(&self) -> StoreResult<std::sync::MutexGuard<'_, State>> {
+        self.state
+            .lock()
+            .map_err(|_| StoreError::new("in-memory directory lock poisoned"))
+    }
+}
+
+impl KeyDirectory for MemoryDirectory {
+    fn sync_users(&self, members: &[Member]) -> StoreResult<()> {
+        let mut state = self.state()?;
+        let mut changed: bool = false;
+        let revision: i64 = state.serial + 1;
+        for member in members {
+            if state.users.get(&member.user_id) != Some(member) {
+                state.users.insert(member.user_id.clone(), member.clone());
+                state.revisions.insert(member.user_id.clone(), revision);
+                changed = true;
+            }
+        }
+        if changed {
+            state.serial += 1;
+        }
+        Ok(())
+    }
+
+    fn upsert_pubkey(&self, user_id: &str, name: &str, pubkey: &str) -> StoreResult<()> {
+        let mut state = self.state()?;
+        let now: i64 = util::unix_timestamp();
+        state
+            .users
+            .entry(user_id.to_string())
+            .or_insert_with(|| Member::new(user_id, name, ""))
+            .deleted = false;
+        state
+            .pubkeys
+            .insert(user_id.to_string(), pubkey.to_string());
+        let revision: i64 = state.serial + 1;
+        state.revisions.insert(user_id.to_string(), revision);
+
+        for key in state.keys.iter_mut() {
+            if key.user_id == user_id && key.revoked_at.is_none() {
+                key.revoked_at = Some(now);
+                key.revocation_reason = Some("superseded".to_string());
+            }
+        }
+        state.keys.push(Key {
+            user_id: user_id.to_string(),
+            pubkey: pubkey.to_string(),
+            fingerprint: crypto::fingerprint(pubkey),
+            created_at: now,
+            revoked_at: None,
+            revocation_reason: None,
+        });
+
+        let entry = LogEntry {
+            leaf_index: state.log.len() as i64,
+            pubkey: pubkey.to_string(),
+            created_at: now,
+        };
+        state
+            .log
+            .push((user_id.to_string(), entry, log_leaf_hash(user_id, pubkey)));
+        state.serial += 1;
+        Ok(())
+    }
+
+    fn revoke_key(&self, user_id: &str, reason: &str) -> StoreResult<Option<Key>> {
+        let mut state = self.state()?;
+        let now: i64 = util::unix_timestamp();
+        let revoked: Option<Key> = match state
+            .keys
+            .iter_mut()
+            .find(|k| k.user_id == user_id && k.revoked_at.is_none())
+        {
+            Some(key) => {
+                key.revoked_at = Some(now);
+                key.revocation_reason = Some(reason.to_string());
+                Some(key.clone())
+            }
+            None => None,
+        };
+        if revoked.is_some() {
+            state.pubkeys.remove(user_id);
+            let revision: i64 = state.serial + 1;
+            state.revisions.insert(user_id.to_string(), revision);
+            state.serial += 1;
+        }
+        Ok(revoked)
+    }
+
+    fn delete_user(&self, user_id: &str) -> StoreResult<bool> {
+        let mut state = self.state()?;
+        let now: i64 = util::unix_timestamp();
+        let mut revoked: bool = false;
+        for key in state.keys.iter_mut() {
+            if key.user_id == user_id && key.revoked_at.is_none() {
+                key.revoked_at = Some(now);
+                key.revocation_reason = Some("user deleted".to_string());
+                revoked = true;
+            }
+        }
+        state.pubkeys.remove(user_id);
+        state.pending_keys.remove(user_id);
+        for token in state.api_tokens.values_mut() {
+            if token.user_id == user_id && token.revoked_at.is_none() {
+                token.revoked_at = Some(now);
+            }
+        }
+        let tombstoned: bool = match state.users.get_mut(user_id) {
+            Some(member) if !member.deleted || revoked => {
+                member.deleted = true;
+                true
+            }
+            _ => false,
+        };
+        if tombstoned {
+            state.serial += 1;
+            let serial: i64 = state.serial;
+            state.revisions.insert(user_id.to_string(), serial);
+        }
+        Ok(tombstoned)
+    }
+
+    fn users(&self) -> StoreResult<Vec<User>> {
+        let state = self.state()?;
+        let mut users: Vec<User> = state.users.values().map(|m| state.user(m)).collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(users)
+    }
+
+    fn user(&self, user_id: &str) -> StoreResult<Option<User>> {
+        let state = self.state()?;
+        Ok(state.users.get(user_id).map(|m| state.user(m)))
+    }
+
+    fn user_by_name(&self, name: &str) -> StoreResult<Option<User>> {
+        let state = self.state()?;
+        Ok(state
+            .users
+            .values()
+            .filter(|m| m.name == name)
+            .min_by_key(|m| m.deleted)
+            .map(|m| state.user(m)))
+    }
+
+    fn changes(&self, since: i64) -> StoreResult<Vec<User>> {
+        let state = self.state()?;
+        let mut users: Vec<User> = state
+            .users
+            .values()
+            .map(|m| state.user(m))
+            .filter(|u| u.revision > since)
+            .collect();
+        users.sort_by(|a, b| (a.revision, &a.name).cmp(&(b.revision, &b.name)));
+        Ok(users)
+    }
+
+    fn current_key(&self, user_id: &str) -> StoreResult<Option<Key>> {
+        Ok(self
+            .state()?
+            .keys
+            .iter()
+            .rev()
+            .find(|k| k.user_id == user_id && k.revoked_at.is_none())
+            .cloned())
+    }
+
+    fn key_history(&self, user_id: &str) -> StoreResult<Vec<Key>> {
+        Ok(self
+            .state()?
+            .keys
+            .iter()
+            .filter(|k| k.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    fn key_by_fingerprint(&self, fingerprint: &str) -> StoreResult<Option<Key>> {
+        Ok(self
+            .state()?
+            .keys
+            .iter()
+            .rev()
+            .find(|k| k.fingerprint.as_deref() == Some(fingerprint))
+            .cloned())
+    }
+
+    fn directory_serial(&self) -> StoreResult<i64> {
+        Ok(self.state()?.serial)
+    }
+
+    fn log_leaf_hashes(&self) -> StoreResult<Vec<merkle::Hash>> {
+        Ok(self.state()?.log.iter().map(|(_, _, hash)| *hash).collect())
+    }
+
+    fn log_entries(&self, user_id: &str) -> StoreResult<Vec<LogEntry>> {
+        Ok(self
+            .state()?
+            .log
+            .iter()
+            .filter(|(id, _, _)| id == user_id)
+            .map(|(_, entry, _)| entry.clone())
+            .collect())
+    }
+
+    fn upsert_pending_key(
+        &self,
+        user_id: &str,
+        name: &str,
+        pubkey: &str,
+        nonce_hash: &str,
+    ) -> StoreResult<()> {
+        self.state()?.pending_keys.insert(
+            user_id.to_string(),
+            PendingKey {
+                user_id: user_id.to_string(),
+                name: name.to_string(),
+                pubkey: pubkey.to_string(),
+                nonce_hash: nonce_hash.to_string(),
+                created_at: util::unix_timestamp(),
+            },
+        );
+        Ok(())
+    }
+
+    fn pending_key(&self, user_id: &str) -> StoreResult<Option<PendingKey>> {
+        Ok(self.state()?.pending_keys.get(user_id).cloned())
+    }
+
+    fn delete_pending_key(&self, user_id: &str) -> StoreResult<()> {
+        self.state()?.pending_keys.remove(user_id);
+        Ok(())
+    }
+
+    fn append_audit_event(
+        &self,
+        actor: &str,
+        action: &str,
+        subject: &str,
+        detail: &str,
+    ) -> StoreResult<AuditEvent> {
+        let mut state = self.state()?;
+        let prev_hash: String = state
+            .audit
+            .last()
+            .map_or_else(|| AUDIT_GENESIS_HASH.to_string(), |e| e.hash.clone());
+        let created_at: i64 = util::unix_timestamp();
+        let event = AuditEvent {
+            id: state.audit.len() as i64 + 1,
+            created_at,
+            actor: actor.to_string(),
+            action: action.to_string(),
+            subject: subject.to_string(),
+            detail: detail.to_string(),
+            hash: audit_hash(&prev_hash, created_at, actor, action, subject, detail),
+            prev_hash,
+        };
+        state.audit.push(event.clone());
+        Ok(event)
+    }
+
+    fn audit_events(&self, query: &AuditQuery) -> StoreResult<Vec<AuditEvent>> {
+        Ok(self
+            .state()?
+            .audit
+            .iter()
+            .filter(|e| query.subject.iter().all(|s| &e.subject == s))
+            .filter(|e| query.actor.iter().all(|a| &e.actor == a))
+            .filter(|e| query.action.iter().all(|a| &e.action == a))
+            .filter(|e| query.since.iter().all(|&since| e.created_at >= since))
+            .filter(|e| query.after_id.iter().all(|&after_id| e.id > after_id))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+
+    fn insert_api_token(&self, token: &ApiToken) -> StoreResult<()> {
+        self.state()?
+            .api_tokens
+            .insert(token.token_hash.clone(), token.clone());
+        Ok(())
+    }
+
+    fn api_token(&self, token_hash: &str) -> StoreResult<Option<ApiToken>> {
+        Ok(self.state()?.api_tokens.get(token_hash).cloned())
+    }
+
+    fn revoke_api_tokens(&self, user_id: &str) -> StoreResult<usize> {
+        let mut state = self.state()?;
+        let now: i64 = util::unix_timestamp();
+        let mut revoked: usize = 0;
+        for token in state.api_tokens.values_mut() {
+            if token.user_id == user_id && token.revoked_at.is_none() {
+                token.revoked_at = Some(now);
+                revoked += 1;
+            }
+        }
+        Ok(revoked)
+    }
+
+    fn backup(&self, _path: &str) -> StoreResult<()> {
+        Err(StoreError::new(
+            "The in-memory directory cannot be backed up, use `export`",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store;
+
+    #[test]
+    fn test_conformance() {
+        store::conformance(&MemoryDirectory::default());
+    }
+}
@@ -0,0 +1,657 @@
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, Connection, OptionalExtension, Result, ToSql, TransactionBehavior};
+use std::vec::Vec;
+
+use crate::crypto;
+use crate::merkle;
+use crate::migrations;
+use crate::store::{
+    audit_hash, log_leaf_hash, ApiToken, AuditEvent, AuditQuery, Key, KeyDirectory, LogEntry,
+    Member, PendingKey, StoreResult, User, AUDIT_GENESIS_HASH,
+};
+use crate::util;
+
+type DbConnection = PooledConnection<SqliteConnectionManager>;
+
+const POOL_SIZE: u32 = 8;
+
+/// The default backend, a SQLite database file shared between the bot thread and the HTTP workers.
+/// WAL mode lets readers continue while a writer commits and `busy_timeout` queues concurrent writers.
+pub struct SqliteDirectory {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl SqliteDirectory {
+    pub fn open(path: &str) -> StoreResult<SqliteDirectory> {
+        log::info!("Opening SQLite3 database {}...", path);
+        let manager = SqliteConnectionManager::file(path).with_init(|conn| {
+            conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA busy_timeout = 5000;")
+        });
+        SqliteDirectory::with_pool(Pool::builder().max_size(POOL_SIZE).build(manager)?)
+    }
+
+    /// A private database living as long as its single pooled connection, for tests.
+    #[cfg(test)]
+    pub fn open_in_memory() -> StoreResult<SqliteDirectory> {
+        let manager = SqliteConnectionManager::memory();
+        SqliteDirectory::with_pool(Pool::builder().max_size(1).build(manager)?)
+    }
+
+    fn with_pool(pool: Pool<SqliteConnectionManager>) -> StoreResult<SqliteDirectory> {
+        let mut conn: DbConnection = pool.get()?;
+        migrations::migrate(&mut conn)?;
+        Ok(SqliteDirectory { pool })
+    }
+
+    fn get_connection(&self) -> StoreResult<DbConnection> {
+        Ok(self.pool.get()?)
+    }
+}
+
+impl KeyDirectory for SqliteDirectory {
+    fn sync_users(&self, members: &[Member]) -> StoreResult<()> {
+        let mut conn: DbConnection = self.get_connection()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        sync_users(&tx, members)?;
+        Ok(tx.commit()?)
+    }
+
+    fn upsert_pubkey(&self, user_id: &str, name: &str, pubkey: &str) -> StoreResult<()> {
+        let mut conn: DbConnection = self.get_connection()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        store_pubkey(&tx, user_id, name, pubkey)?;
+        Ok(tx.commit()?)
+    }
+
+    fn revoke_key(&self, user_id: &str, reason: &str) -> StoreResult<Option<Key>> {
+        let mut conn: DbConnection = self.get_connection()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let current: Option<Key> =
+            select_keys(&tx, "WHERE user_id = ? AND revoked_at IS NULL", user_id)?
+                .into_iter()
+                .last();
+        let revoked: Option<Key> = match current {
+            Some(key) => Some(revoke_current_key(&tx, key, reason)?),
+            None => None,
+        };
+        tx.commit()?;
+        Ok(revoked)
+    }
+
+    fn delete_user(&self, user_id: &str) -> StoreResult<bool> {
+        let mut conn: DbConnection = self.get_connection()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let revoked: usize = tx.execute(
+            "UPDATE keys SET revoked_at = ?1, revocation_reason = 'user deleted' WHERE user_id = ?2 AND revoked_at IS NULL",
+            params![util::unix_timestamp(), user_id],
+        )?;
+        tx.execute(
+            "DELETE FROM pending_keys WHERE user_id = ?1",
+            params![user_id],
+        )?;
+        revoke_api_tokens(&tx, user_id)?;
+        // the revocation and the tombstone count as one change
+        let tombstoned: usize = tx.execute(
+            "UPDATE users SET deleted = 1, pubkey = '', revision = ?1 WHERE user_id = ?2 AND (deleted = 0 OR ?3)",
+            params![next_revision(&tx)?, user_id, revoked > 0],
+        )?;
+        if tombstoned > 0 {
+            bump_directory_serial(&tx)?;
+        }
+        tx.commit()?;
+        Ok(tombstoned > 0)
+    }
+
+    fn users(&self) -> StoreResult<Vec<User>> {
+        let conn: DbConnection = self.get_connection()?;
+        Ok(select_users(&conn, "ORDER BY u.name", &[])?)
+    }
+
+    fn user(&self, user_id: &str) -> StoreResult<Option<User>> {
+        let conn: DbConnection = self.get_connection()?;
+        let users: Vec<User> = select_users(&conn, "WHERE u.user_id = ?", &[&user_id])?;
+        Ok(users.into_iter().next())
+    }
+
+    fn user_by_name(&self, name: &str) -> StoreResult<Option<User>> {
+        let conn: DbConnection = self.get_connection()?;
+        let users: Vec<User> = select_users(
+            &conn,
+            "WHERE u.name = ? ORDER BY u.deleted LIMIT 1",
+            &[&name],
+        )?;
+        Ok(users.into_iter().next())
+    }
+
+    fn changes(&self, since: i64) -> StoreResult<Vec<User>> {
+        let conn: DbConnection = self.get_connection()?;
+        Ok(select_users(
+            &conn,
+            "WHERE u.revision > ? ORDER BY u.revision, u.name",
+            &[&since],
+        )?)
+    }
+
+    fn current_key(&self, user_id: &str) -> StoreResult<Option<Key>> {
+        let conn: DbConnection = self.get_connection()?;
+        let keys: Vec<Key> =
+            select_keys(&conn, "WHERE user_id = ? AND revoked_at IS NULL", user_id)?;
+        Ok(keys.into_iter().last())
+    }
+
+    fn key_history(&self, user_id: &str) -> StoreResult<Vec<Key>> {
+        let conn: DbConnection = self.get_connection()?;
+        Ok(select_keys(&conn, "WHERE user_id = ?", user_id)?)
+    }
+
+    fn key_by_fingerprint(&self, fingerprint: &str) -> StoreResult<Option<Key>> {
+        let conn: DbConnection = self.get_connection()?;
+        let keys: Vec<Key> = select_keys(&conn, "WHERE fingerprint = ?", fingerprint)?;
+        Ok(keys.into_iter().last())
+    }
+
+    fn directory_serial(&self) -> StoreResult<i64> {
+        let conn: DbConnection = self.get_connection()?;
+        Ok(conn.query_row(
+            "SELECT serial FROM directory_serial WHERE id = 0",
+            params![],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn log_leaf_hashes(&self) -> StoreResult<Vec<merkle::Hash>> {
+        let conn: DbConnection = self.get_connection()?;
+        let mut stmt = conn.prepare("SELECT leaf_hash FROM log_leaves ORDER BY leaf_index")?;
+        let mut rows = stmt.query([])?;
+
+        let mut leaf_hashes = Vec::new();
+        while let Some(row) = rows.next()? {
+            let leaf_hash: [u8; 32] = row.get(0)?;
+            leaf_hashes.push(leaf_hash);
+        }
+
+        Ok(leaf_hashes)
+    }
+
+    fn log_entries(&self, user_id: &str) -> StoreResult<Vec<LogEntry>> {
+        let conn: DbConnection = self.get_connection()?;
+        let mut stmt = conn.prepare(
+            "SELECT leaf_index, pubkey, created_at FROM log_leaves WHERE user_id = ? ORDER BY leaf_index",
+        )?;
+        let mut rows = stmt.query([&user_id])?;
+
+        let mut entries = Vec::new();
+        while let Some(row) = rows.next()? {
+            entries.push(LogEntry {
+                leaf_index: row.get(0)?,
+                pubkey: row.get(1)?,
+                created_at: row.get(2)?,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn upsert_pending_key(
+        &self,
+        user_id: &str,
+        name: &str,
+        pubkey: &str,
+        nonce_hash: &str,
+    ) -> StoreResult<()> {
+        let conn: DbConnection = self.get_connection()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO pending_keys (user_id, name, pubkey, nonce_hash, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![user_id, name, pubkey, nonce_hash, util::unix_timestamp()],
+        )?;
+        Ok(())
+    }
+
+    fn pending_key(&self, user_id: &str) -> StoreResult<Option<PendingKey>> {
+        let conn: DbConnection = self.get_connection()?;
+        let mut stmt = conn.prepare(
+            "SELECT user_id, name, pubkey, nonce_hash, created_at FROM pending_keys WHERE user_id = ?",
+        )?;
+        let mut rows = stmt.query([&user_id])?;
+
+        match rows.next()? {
+            Some(row) => Ok(Some(PendingKey {
+                user_id: row.get(0)?,
+                name: row.get(1)?,
+                pubkey: row.get(2)?,
+                nonce_hash: row.get(3)?,
+                created_at: row.get(4)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn delete_pending_key(&self, user_id: &str) -> StoreResult<()> {
+        let conn: DbConnection = self.get_connection()?;
+        conn.execute(
+            "DELETE FROM pending_keys WHERE user_id = ?1",
+            params![user_id],
+        )?;
+        Ok(())
+    }
+
+    fn append_audit_event(
+        &self,
+        actor: &str,
+        action: &str,
+        subject: &str,
+        detail: &str,
+    ) -> StoreResult<AuditEvent> {
+        let mut conn: DbConnection = self.get_connection()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let prev_hash: String = tx
+            .query_row(
+                "SELECT hash FROM audit_events ORDER BY id DESC LIMIT 1",
+                params![],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or_else(|| AUDIT_GENESIS_HASH.to_string());
+        let created_at: i64 = util::unix_timestamp();
+        let hash: String = audit_hash(&prev_hash, created_at, actor, action, subject, detail);
+        tx.execute(
+            "INSERT INTO audit_events (created_at, actor, action, subject, detail, prev_hash, hash)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![created_at, actor, action, subject, detail, prev_hash, hash],
+        )?;
+        let id: i64 = tx.last_insert_rowid();
+        tx.commit()?;
+        Ok(AuditEvent {
+            id,
+            created_at,
+            actor: actor.to_string(),
+            action: action.to_string(),
+            subject: subject.to_string(),
+            detail: detail.to_string(),
+            prev_hash,
+            hash,
+        })
+    }
+
+    fn audit_events(&self, query: &AuditQuery) -> StoreResult<Vec<AuditEvent>> {
+        let conn: DbConnection = self.get_connection()?;
+        let mut filters: Vec<&str> = Vec::new();
+        let mut values: Vec<&dyn ToSql> = Vec::new();
+        if let Some(subject) = &query.subject {
+            filters.push("subject = ?");
+            values.push(subject);
+        }
+        if let Some(actor) = &query.actor {
+            filters.push("actor = ?");
+            values.push(actor);
+        }
+        if let Some(action) = &query.action {
+            filters.push("action = ?");
+            values.push(action);
+        }
+        if let Some(since) = &query.since {
+            filters.push("created_at >= ?");
+            values.push(since);
+        }
+        if let Some(after_id) = &query.after_id {
+            filters.push("id > ?");
+            values.push(after_id);
+        }
+        // a negative LIMIT is no limit in SQLite
+        let limit: i64 = query.limit.map_or(-1, |limit| limit as i64);
+        values.push(&limit);
+        let clause: String = if filters.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", filters.join(" AND "))
+        };
+
+        let mut stmt =
+            conn.prepare(&format!("{} {} ORDER BY id LIMIT ?", AUDIT_COLUMNS, clause))?;
+        let mut rows = stmt.query(values.as_slice())?;
+
+        let mut events = Vec::new();
+        while let Some(row) = rows.next()? {
+            events.push(AuditEvent {
+                id: row.get(0)?,
+                created_at: row.get(1)?,
+                actor: row.get(2)?,
+                action: row.get(3)?,
+                subject: row.get(4)?,
+                detail: row.get(5)?,
+                prev_hash: row.get(6)?,
+                hash: row.get(7)?,
+            });
+        }
+
+        Ok(events)
+    }
+
+    fn insert_api_token(&self, token: &ApiToken) -> StoreResult<()> {
+        let conn: DbConnection = self.get_connection()?;
+        conn.execute(
+            "INSERT INTO api_tokens (token_hash, user_id, created_at, expires_at, revoked_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                token.token_hash,
+                token.user_id,
+                token.created_at,
+                token.expires_at,
+                token.revoked_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn api_token(&self, token_hash: &str) -> StoreResult<Option<ApiToken>> {
+        let conn: DbConnection = self.get_connection()?;
+        Ok(conn
+            .query_row(
+                "SELECT token_hash, user_id, created_at, expires_at, revoked_at FROM api_tokens WHERE token_hash = ?1",
+                params![token_hash],
+                |row| {
+                    Ok(ApiToken {
+                        token_hash: row.get(0)?,
+                        user_id: row.get(1)?,
+                        created_at: row.get(2)?,
+                        expires_at: row.get(3)?,
+                        revoked_at: row.get(4)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    fn revoke_api_tokens(&self, user_id: &str) -> StoreResult<usize> {
+        let conn: DbConnection = self.get_connection()?;
+        Ok(revoke_api_tokens(&conn, user_id)?)
+    }
+
+    fn backup(&self, path: &str) -> StoreResult<()> {
+        let conn: DbConnection = self.get_connection()?;
+        conn.execute("VACUUM INTO ?1", params![path])?;
+        Ok(())
+    }
+}
+
+const AUDIT_COLUMNS: &str =
+    "SELECT id, created_at, actor, action, subject, detail, prev_hash, hash FROM audit_events";
+
+/// The current key is the user's only unrevoked one, see `insert_key`.
+const USER_COLUMNS: &str = "SELECT u.user_id, u.name, u.real_name, u.deleted, u.pubkey, k.fingerprint, k.created_at, u.revision FROM users u
+     LEFT JOIN keys k ON k.user_id = u.user_id AND k.revoked_at IS NULL";
+
+fn select_users(conn: &Connection, clause: &str, values: &[&dyn ToSql]) -> Result<Vec<User>> {
+    let mut stmt = conn.prepare(&format!("{} {}", USER_COLUMNS, clause))?;
+    let mut rows = stmt.query(values)?;
+
+    let mut users = Vec::new();
+    while let Some(row) = rows.next()? {
+        users.push(User {
+            user_id: row.get(0)?,
+            name: row.get(1)?,
+            real_name: row.get(2)?,
+            deleted: row.get(3)?,
+            pubkey: row.get(4)?,
+            fingerprint: row.get(5)?,
+            updated_at: row.get(6)?,
+            revision: row.get(7)?,
+        });
+    }
+
+    Ok(users)
+}
+
+fn sync_users(conn: &Connection, members: &[Member]) -> Result<()> {
+    let revision: i64 = next_revision(conn)?;
+    let mut changed: usize = 0;
+    {
+        let mut stmt = conn.prepare_cached(
+            "INSERT INTO users (user_id, name, real_name, deleted, pubkey, revision) VALUES (?1, ?2, ?3, ?4, '', ?5)
+             ON CONFLICT(user_id) DO UPDATE SET name = excluded.name, real_name = excluded.real_name,
+             deleted = excluded.deleted, revision = excluded.revision
+             WHERE users.name != excluded.name OR users.real_name != excluded.real_name
+             OR users.deleted != excluded.deleted",
+        )?;
+        for member in members {
+            changed += stmt.execute(params![
+                member.user_id,
+                member.name,
+                member.real_name,
+                member.deleted,
+                revision
+            ])?;
+        }
+    }
+    log::debug!("Synced {} users, {} changed", members.len(), changed);
+    if changed > 0 {
+        bump_directory_serial(conn)?;
+    }
+    Ok(())
+}
+
+fn store_pubkey(conn: &Connection, user_id: &str, name: &str, pubkey: &str) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO users (user_id, name, pubkey, revision) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(user_id) DO UPDATE SET pubkey = excluded.pubkey, revision = excluded.revision, deleted = 0",
+    )?
+    .execute(params![user_id, name, pubkey, next_revision(conn)?])?;
+    insert_key(conn, user_id, pubkey)?;
+    append_log_leaf(conn, user_id, pubkey)?;
+    bump_directory_serial(conn)
+}
+
+/// Records a new current key for the user, any previous key is revoked as superseded.
+fn insert_key(conn: &Connection, user_id: &str, pubkey: &str) -> Result<()> {
+    let now: i64 = util::unix_timestamp();
+    conn.prepare_cached(
+        "UPDATE keys SET revoked_at = ?1, revocation_reason = 'superseded' WHERE user_id = ?2 AND revoked_at IS NULL",
+    )?
+    .execute(params![now, user_id])?;
+    conn.prepare_cached(
+        "INSERT INTO keys (user_id, pubkey, fingerprint, created_at) VALUES (?1, ?2, ?3, ?4)",
+    )?
+    .execute(params![user_id, pubkey, crypto::fingerprint(pubkey), now])?;
+    Ok(())
+}
+
+/// Revokes `key`, the user's current one, and clears it from the directory.
+fn revoke_current_key(conn: &Connection, key: Key, reason: &str) -> Result<Key> {
+    let now: i64 = util::unix_timestamp();
+    conn.prepare_cached(
+        "UPDATE keys SET revoked_at = ?1, revocation_reason = ?2 WHERE user_id = ?3 AND revoked_at IS NULL",
+    )?
+    .execute(params![now, reason, key.user_id])?;
+    conn.prepare_cached("UPDATE users SET pubkey = '', revision = ?1 WHERE user_id = ?2")?
+        .execute(params![next_revision(conn)?, key.user_id])?;
+    bump_directory_serial(conn)?;
+    Ok(Key {
+        revoked_at: Some(now),
+        revocation_reason: Some(reason.to_string()),
+        ..key
+    })
+}
+
+const KEY_COLUMNS: &str =
+    "SELECT user_id, pubkey, fingerprint, created_at, revoked_at, revocation_reason FROM keys";
+
+fn select_keys(conn: &Connection, filter: &str, value: &str) -> Result<Vec<Key>> {
+    let mut stmt = conn.prepare(&format!("{} {} ORDER BY id", KEY_COLUMNS, filter))?;
+    let mut rows = stmt.query([&value])?;
+
+    let mut keys = Vec::new();
+    while let Some(row) = rows.next()? {
+        keys.push(Key {
+            user_id: row.get(0)?,
+            pubkey: row.get(1)?,
+            fingerprint: row.get(2)?,
+            created_at: row.get(3)?,
+            revoked_at: row.get(4)?,
+            revocation_reason: row.get(5)?,
+        });
+    }
+
+    Ok(keys)
+}
+
+/// Keys stored before key history existed become each user's first, current key.
+pub fn backfill_keys(conn: &Connection) -> Result<()> {
+    let recorded: i64 = conn.query_row("SELECT COUNT(*) FROM keys", params![], |row| row.get(0))?;
+    if recorded > 0 {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare("SELECT user_id, pubkey FROM users WHERE pubkey != ''")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let user_id: String = row.get(0)?;
+        let pubkey: String = row.get(1)?;
+        insert_key(conn, &user_id, &pubkey)?;
+    }
+    Ok(())
+}
+
+fn append_log_leaf(conn: &Connection, user_id: &str, pubkey: &str) -> Result<()> {
+    let leaf_index: i64 = conn.query_row("SELECT COUNT(*) FROM log_leaves", params![], |row| {
+        row.get(0)
+    })?;
+    let leaf_hash: merkle::Hash = log_leaf_hash(user_id, pubkey);
+    conn.prepare_cached(
+        "INSERT INTO log_leaves (leaf_index, user_id, pubkey, leaf_hash, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?
+    .execute(params![leaf_index, user_id, pubkey, &leaf_hash[..], util::unix_timestamp()])?;
+    Ok(())
+}
+
+/// Keys stored before the transparency log existed are appended once, in `name` order.
+pub fn backfill_log(conn: &Connection) -> Result<()> {
+    let logged: i64 = conn.query_row("SELECT COUNT(*) FROM log_leaves", params![], |row| {
+        row.get(0)
+    })?;
+    if logged > 0 {
+        return Ok(());
+    }
+    let mut stmt =
+        conn.prepare("SELECT user_id, pubkey FROM users WHERE pubkey != '' ORDER BY name")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let user_id: String = row.get(0)?;
+        let pubkey: String = row.get(1)?;
+        append_log_leaf(conn, &user_id, &pubkey)?;
+    }
+    Ok(())
+}
+
+/// Chains the audit events recorded before entries were hash-chained, in id order.
+pub fn backfill_audit_chain(conn: &Connection) -> Result<()> {
+    let mut events: Vec<(i64, i64, String, String, String, String)> = Vec::new();
+    {
+        let mut stmt = conn.prepare(
+            "SELECT id, created_at, actor, action, subject, detail FROM audit_events ORDER BY id",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            events.push((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+            ));
+        }
+    }
+    let mut prev_hash: String = AUDIT_GENESIS_HASH.to_string();
+    for (id, created_at, actor, action, subject, detail) in events {
+        let hash: String = audit_hash(&prev_hash, created_at, &actor, &action, &subject, &detail);
+        conn.execute(
+            "UPDATE audit_events SET prev_hash = ?1, hash = ?2 WHERE id = ?3",
+            params![prev_hash, hash, id],
+        )?;
+        prev_hash = hash;
+    }
+    Ok(())
+}
+
+/// Revokes the unrevoked API tokens of `user_id`, returns how many.
+fn revoke_api_tokens(conn: &Connection, user_id: &str) -> Result<usize> {
+    conn.execute(
+        "UPDATE api_tokens SET revoked_at = ?1 WHERE user_id = ?2 AND revoked_at IS NULL",
+        params![util::unix_timestamp(), user_id],
+    )
+}
+
+/// The serial the directory will have once the running transaction bumps it, stored as the changed users' revision.
+fn next_revision(conn: &Connection) -> Result<i64> {
+    conn.query_row(
+        "SELECT serial + 1 FROM directory_serial WHERE id = 0",
+        params![],
+        |row| row.get(0),
+    )
+}
+
+/// Every change to the directory increments its serial so clients can reject rolled back snapshots.
+fn bump_directory_serial(conn: &Connection) -> Result<()> {
+    conn.prepare_cached("UPDATE directory_serial SET serial = serial + 1 WHERE id = 0")?
+        .execute(params![])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store;
+
+    #[test]
+    fn test_conformance() {
+        store::conformance(&SqliteDirectory::open_in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_backup() {
+        let path: String = format!(
+            "{}/slackrypt-backup-test-{}.db3",
+            std::env::temp_dir().display(),
+            std::process::id()
+        );
+        let directory = SqliteDirectory::open_in_memory().unwrap();
+        directory.upsert_pubkey("U1234ABC", "jeff", "KEY1").unwrap();
+        directory.backup(&path).unwrap();
+        assert!(directory.backup(&path).is_err());
+
+        let restored = SqliteDirectory::open(&path).unwrap();
+        assert_eq!(restored.users().unwrap(), directory.users().unwrap());
+        assert_eq!(restored.log_leaf_hashes().unwrap().len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_audit_events_append_only() {
+        let directory = SqliteDirectory::open_in_memory().unwrap();
+        directory
+            .append_audit_event("bot", "key-change-notified", "U1234ABC", "{}")
+            .unwrap();
+        let conn: DbConnection = directory.get_connection().unwrap();
+        assert!(conn
+            .execute("UPDATE audit_events SET detail = '{\"to\":\"nobody\"}'", [])
+            .is_err());
+        assert!(conn.execute("DELETE FROM audit_events", []).is_err());
+    }
+
+    #[test]
+    fn test_sync_users() {
+        let directory = SqliteDirectory::open_in_memory().unwrap();
+        let ids: Vec<String> = (0..5000).map(|i| format!("U{:05}", i)).collect();
+        let users: Vec<Member> = ids.iter().map(|id| Member::new(id, "member", "")).collect();
+        directory.sync_users(&users).unwrap();
+        assert_eq!(directory.directory_serial().unwrap(), 1);
+
+        // unchanged members do not bump the serial, renamed ones do
+        directory.sync_users(&users).unwrap();
+        assert_eq!(directory.directory_serial().unwrap(), 1);
+        directory
+            .sync_users(&[Member::new("U00001", "renamed", "")])
+            .unwrap();
+        assert_eq!(directory.directory_serial().unwrap(), 2);
+        assert_eq!(directory.users().unwrap().len(), 5000);
+    }
+}
@@ -0,0 +1,258 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::metrics;
+use crate::util;
+
+/// The first reconnect waits about this long, every further failure doubles it.
+const BACKOFF_BASE_MS: u64 = 1_000;
+
+/// Reconnects never wait longer than this.
+const BACKOFF_MAX_MS: u64 = 300_000;
+
+/// A connection that stayed up this long is healthy, the next failure starts the backoff over.
+const STABLE_SECS: u64 = 60;
+
+/// How long SIGTERM waits for the bot to disconnect before the process exits anyway.
+const SHUTDOWN_GRACE_SECS: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BotState {
+    Starting,
+    Connected,
+    Reconnecting,
+    /// Gave up after too many failed reconnects, only a restart brings the bot back.
+    Failed,
+    Stopped,
+}
+
+impl BotState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BotState::Starting => "starting",
+            BotState::Connected => "connected",
+            BotState::Reconnecting => "reconnecting",
+            BotState::Failed => "failed",
+            BotState::Stopped => "stopped",
+        }
+    }
+}
+
+/// The bot connection as reported to the HTTP side.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Health {
+    pub state: BotState,
+    /// When `state` was entered.
+    pub since: i64,
+    /// Reconnects since the last stable connection.
+    pub reconnects: u32,
+    pub last_error: Option<String>,
+}
+
+static HEALTH: Mutex<Health> = Mutex::new(Health {
+    state: BotState::Starting,
+    since: 0,
+    reconnects: 0,
+    last_error: None,
+});
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Closes the current connection on shutdown, registered by the transport once it is connected.
+type StopHook = Box<dyn Fn() + Send>;
+
+static STOP_HOOK: Mutex<Option<StopHook>> = Mutex::new(None);
+
+pub fn health() -> Health {
+    HEALTH.lock().unwrap().clone()
+}
+
+fn set_state(state: BotState, reconnects: u32, error: Option<String>) {
+    let mut health = HEALTH.lock().unwrap();
+    if health.state != state {
+        health.since = util::unix_timestamp();
+    }
+    health.state = state;
+    health.reconnects = reconnects;
+    if error.is_some() {
+        health.last_error = error;
+    }
+}
+
+/// Called by the transport once it is connected to Slack.
+pub fn connected() {
+    let reconnects: u32 = health().reconnects;
+    set_state(BotState::Connected, reconnects, None);
+}
+
+pub fn on_stop<F: Fn() + Send + 'static>(hook: F) {
+    *STOP_HOOK.lock().unwrap() = Some(Box::new(hook));
+}
+
+pub fn is_shutting_down() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Blocks until shutdown, for transports that have no connection of their own to wait on.
+pub fn wait_for_shutdown() {
+    while !is_shutting_down() {
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// The delay before reconnect number `attempt` (starting at 0): exponential with "equal jitter", i.e. somewhere
+/// between half and all of the capped exponential delay, `jitter` being uniform in `[0, 1)`.
+pub fn backoff_delay(attempt: u32, jitter: f64) -> Duration {
+    let exp: u64 = BACKOFF_BASE_MS
+        .saturating_mul(1u64 << attempt.min(32))
+        .min(BACKOFF_MAX_MS);
+    Duration::from_millis(exp / 2 + (exp as f64 / 2.0 * jitter) as u64)
+}
+
+/// Sleeps for `delay` unless a shutdown starts first.
+fn sleep_unless_shutdown(delay: Duration) {
+    let until: Instant = Instant::now() + delay;
+    while !is_shutting_down() && Instant::now() < until {
+        thread::sleep(Duration::from_millis(200).min(until - Instant::now()));
+    }
+}
+
+/// Runs the bot connection `run` until shutdown, restarting it with backoff whenever it returns, fails or panics.
+/// After `max_reconnects` failures in a row without a stable connection it gives up and reports `Failed`.
+pub fn supervise<F: FnMut() -> Result<(), String>>(max_reconnects: u32, run: F) {
+    supervise_with(max_reconnects, run, sleep_unless_shutdown)
+}
+
+/// `supervise`, waiting out each backoff delay with `sleep`.
+fn supervise_with<F, S>(max_reconnects: u32, mut run: F, mut sleep: S)
+where
+    F: FnMut() -> Result<(), String>,
+    S: FnMut(Duration),
+{
+    let mut reconnects: u32 = 0;
+    set_state(BotState::Starting, reconnects, None);
+    loop {
+        let started: Instant = Instant::now();
+        let error: String = match panic::catch_unwind(AssertUnwindSafe(&mut run)) {
+            Ok(Ok(())) => String::from("connection closed"),
+            Ok(Err(e)) => e,
+            Err(panic) => match panic.downcast_ref::<String>() {
+                Some(message) => format!("panicked: {}", message),
+                None => match panic.downcast_ref::<&str>() {
+                    Some(message) => format!("panicked: {}", message),
+                    None => String::from("panicked"),
+                },
+            },
+        };
+        if is_shutting_down() {
+            log::info!("Slack bot stopped");
+            set_state(BotState::Stopped, reconnects, None);
+            return;
+        }
+
+        if started.elapsed() >= Duration::from_secs(STABLE_SECS) {
+            reconnects = 0;
+        }
+        if reconnects >= max_reconnects {
+            log::error!(
+                "Slack bot gave up after {} reconnects, last error: {}",
+                reconnects,
+                error
+            );
+            set_state(BotState::Failed, reconnects, Some(error));
+            return;
+        }
+        let delay: Duration = backoff_delay(reconnects, rand::thread_rng().gen());
+        reconnects += 1;
+        metrics::bot_reconnect();
+        log::warn!(
+            "Slack bot disconnected ({}), reconnect {} of {} in {:?}",
+            error,
+            reconnects,
+            max_reconnects,
+            delay
+        );
+        set_state(BotState::Reconnecting, reconnects, Some(error));
+        sleep(delay);
+    }
+}
+
+/// Stops the bot, gives it a moment to say goodbye to Slack and exits. Rocket 0.4 cannot be stopped gracefully,
+/// in-flight requests are cut off.
+pub fn shutdown() {
+    log::info!("Shutting down...");
+    SHUTDOWN.store(true, Ordering::SeqCst);
+    if let Some(stop) = STOP_HOOK.lock().unwrap().as_ref() {
+        stop();
+    }
+    let until: Instant = Instant::now() + Duration::from_secs(SHUTDOWN_GRACE_SECS);
+    while Instant::now() < until && !matches!(health().state, BotState::Stopped | BotState::Failed)
+    {
+        thread::sleep(Duration::from_millis(100));
+    }
+    std::process::exit(0);
+}
+
+/// Shuts down on SIGTERM (e.g. `docker stop`) and SIGINT.
+pub fn handle_signals() -> std::io::Result<()> {
+    let mut signals = signal_hook::iterator::Signals::new([
+        signal_hook::consts::SIGTERM,
+        signal_hook::consts::SIGINT,
+    ])?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            log::info!("Received signal {}", signal);
+            shutdown();
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_delay() {
+        assert_eq!(backoff_delay(0, 0.0), Duration::from_millis(500));
+        assert_eq!(backoff_delay(0, 0.999), Duration::from_millis(999));
+        assert_eq!(backoff_delay(3, 0.0), Duration::from_millis(4_000));
+        assert_eq!(backoff_delay(3, 0.5), Duration::from_millis(6_000));
+        assert_eq!(backoff_delay(20, 0.0), Duration::from_millis(150_000));
+        assert_eq!(
+            backoff_delay(u32::MAX, 0.999),
+            Duration::from_millis(299_850)
+        );
+    }
+
+    #[test]
+    fn test_supervise() {
+        let mut runs: u32 = 0;
+        let mut delays: Vec<Duration> = Vec::new();
+        supervise_with(
+            2,
+            || {
+                runs += 1;
+                match runs {
+                    1 => Err(String::from("login failed")),
+                    2 => panic!("lost the socket"),
+                    _ => Ok(()),
+                }
+            },
+            |delay| delays.push(delay),
+        );
+        assert_eq!(runs, 3);
+        assert_eq!(delays.len(), 2);
+        assert!(delays[0] >= backoff_delay(0, 0.0) && delays[0] <= backoff_delay(0, 0.999));
+        assert!(delays[1] >= backoff_delay(1, 0.0) && delays[1] <= backoff_delay(1, 0.999));
+        let health: Health = health();
+        assert_eq!(health.state, BotState::Failed);
+        assert_eq!(health.reconnects, 2);
+        assert_eq!(health.last_error.as_deref(), Some("connection closed"));
+    }
+}
@@ -0,0 +1,149 @@
+use rand::Rng;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+
+use crate::config;
+use crate::crypto;
+use crate::db;
+use crate::db::{ApiToken, User};
+use crate::store::StoreResult;
+use crate::util;
+
+/// Starts every API token, so they are easy to recognize in configs and logs.
+const TOKEN_PREFIX: &str = "slackrypt_";
+
+const DAY_SECS: i64 = 86_400;
+
+/// Audit log actions of `issue` and `revoke`.
+pub const TOKEN_ISSUED: &str = "api-token-issued";
+pub const TOKENS_REVOKED: &str = "api-tokens-revoked";
+
+/// Creates an API token for `user_id`, valid for `api_token_ttl_days`, and audits it. Only its hash is stored,
+/// the token itself is returned once for the bot to DM to the user.
+pub fn issue(user_id: &str, now: i64) -> StoreResult<(String, ApiToken)> {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    let secret: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let token: String = format!("{}{}", TOKEN_PREFIX, secret);
+    let issued = ApiToken {
+        token_hash: crypto::sha256_hex(token.as_bytes()),
+        user_id: user_id.to_string(),
+        created_at: now,
+        expires_at: now + i64::from(config::get().api_token_ttl_days) * DAY_SECS,
+        revoked_at: None,
+    };
+    db::insert_api_token(&issued)?;
+    db::record_audit_event(
+        user_id,
+        TOKEN_ISSUED,
+        user_id,
+        &serde_json::json!({ "expires_at": issued.expires_at }),
+    )?;
+    Ok((token, issued))
+}
+
+/// Revokes every API token of `user_id` and audits it when there were any. Returns how many were revoked.
+pub fn revoke(actor: &str, user_id: &str) -> StoreResult<usize> {
+    let revoked: usize = db::revoke_api_tokens(user_id)?;
+    if revoked > 0 {
+        db::record_audit_event(
+            actor,
+            TOKENS_REVOKED,
+            user_id,
+            &serde_json::json!({ "tokens": revoked }),
+        )?;
+    }
+    Ok(revoked)
+}
+
+/// The user `token` was issued to, `None` when it is unknown, revoked or expired,
+/// or the user was deactivated in Slack since.
+pub fn authenticate(token: &str, now: i64) -> StoreResult<Option<User>> {
+    let stored: ApiToken = match db::select_api_token(&crypto::sha256_hex(token.as_bytes()))? {
+        Some(stored) if stored.revoked_at.is_none() && stored.expires_at > now => stored,
+        _ => return Ok(None),
+    };
+    Ok(db::get_user(&stored.user_id)?.filter(|user| !user.deleted))
+}
+
+/// The DM carrying a new token, it is the only time the token is shown.
+pub fn token_message(token: &str, expires_at: i64) -> String {
+    format!(
+        "Your API token for the Slackrypt desktop client, valid until <!date^{}^{{date_short}}|{}>:\n`{}`\n\
+         Paste it in the client with File/Set API Token. Keep it secret, anyone holding it can read the key directory. \
+         Run `/slackrypt token revoke` if it leaks.",
+        expires_at, expires_at, token
+    )
+}
+
+/// A workspace member authenticated with `Authorization: Bearer <token>` and one of the tokens the bot issued,
+/// `401 Unauthorized` otherwise.
+pub struct ApiUser {
+    pub user: User,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ApiUser {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let token: Option<&str> = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|v| v.strip_prefix("Bearer "));
+        let user: StoreResult<Option<User>> = match token {
+            Some(token) => authenticate(token.trim(), util::unix_timestamp()),
+            None => Ok(None),
+        };
+        match user {
+            Ok(Some(user)) => Outcome::Success(ApiUser { user }),
+            Ok(None) => {
+                log::warn!("Rejecting unauthenticated request to {}", request.uri());
+                Outcome::Failure((Status::Unauthorized, ()))
+            }
+            Err(e) => {
+                log::error!("Could not check an API token: {}", e);
+                Outcome::Failure((Status::ServiceUnavailable, ()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Member;
+
+    #[test]
+    fn test_authenticate() {
+        db::init_memory();
+        db::upsert_members(&[
+            Member::new("UTOK0001", "holder", ""),
+            Member::new("UTOK0002", "leaver", ""),
+        ])
+        .unwrap();
+        let now: i64 = util::unix_timestamp();
+        let (token, issued) = issue("UTOK0001", now).unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_ne!(issued.token_hash, token);
+
+        let user_id = |token: &str, now: i64| -> Option<String> {
+            authenticate(token, now).unwrap().map(|user| user.user_id)
+        };
+        assert_eq!(user_id(&token, now), Some("UTOK0001".to_string()));
+        assert_eq!(user_id(&token[1..], now), None);
+        assert_eq!(user_id(&token, issued.expires_at), None);
+
+        assert_eq!(revoke("UTOK0001", "UTOK0001").unwrap(), 1);
+        assert_eq!(user_id(&token, now), None);
+        assert_eq!(revoke("UTOK0001", "UTOK0001").unwrap(), 0);
+
+        // deactivated members lose access with their Slack account
+        let (token, _) = issue("UTOK0002", now).unwrap();
+        db::upsert_members(&[Member {
+            deleted: true,
+            ..Member::new("UTOK0002", "leaver", "")
+        }])
+        .unwrap();
+        assert_eq!(user_id(&token, now), None);
+    }
+}
@@ -1,4 +1,7 @@
 use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
 
 pub fn default_dir() -> String {
     String::from(env!("HOME")) + "/.slackrypt-server"
@@ -11,11 +14,28 @@ pub fn get_env_var(var: &str, default: &str) -> String {
     }
 }
 
-pub fn get_init_sh_cmd(base_url: &str) -> String {
+pub fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+pub fn to_base64_str(bytes: &[u8]) -> String {
+    STANDARD.encode(bytes)
+}
+
+pub fn get_init_sh_cmd(base_url: &str, events_url: &str) -> String {
     let mut cmd = String::from("#!/bin/sh\n");
     cmd.push_str("echo \"server_base_url=");
     cmd.push_str(base_url);
-    cmd.push_str("\" >> ~/.slackrypt/slackrypt.properties");
+    cmd.push_str("\" >> ~/.slackrypt/slackrypt.properties\n");
+    cmd.push_str("echo \"server_events_url=");
+    cmd.push_str(events_url);
+    cmd.push_str("\" >> ~/.slackrypt/slackrypt.properties\n");
+    cmd.push_str("curl -sSf ");
+    cmd.push_str(base_url);
+    cmd.push_str("/server.pem.pub > ~/.slackrypt/server.pem.pub");
     cmd
 }
 
@@ -31,7 +51,10 @@ mod tests {
 
     #[test]
     fn test_get_init_sh_cmd() {
-        let expected: &str = "#!/bin/sh\necho \"server_base_url=http://127.0.0.1:8080\" >> ~/.slackrypt/slackrypt.properties";
-        assert_eq!(expected, get_init_sh_cmd("http://127.0.0.1:8080"))
+        let expected: &str = "#!/bin/sh\necho \"server_base_url=http://127.0.0.1:8080\" >> ~/.slackrypt/slackrypt.properties\necho \"server_events_url=http://127.0.0.1:8001/events\" >> ~/.slackrypt/slackrypt.properties\ncurl -sSf http://127.0.0.1:8080/server.pem.pub > ~/.slackrypt/server.pem.pub";
+        assert_eq!(
+            expected,
+            get_init_sh_cmd("http://127.0.0.1:8080", "http://127.0.0.1:8001/events")
+        )
     }
 }